@@ -1,10 +1,18 @@
+/* The rust binding generates this from its cargo features, see
+   rust_binding/build.rs. Without it the defaults below apply. */
+#ifdef LWIP_TUN_FEATURES
+#include "lwipopts_features.h"
+#endif
+
 #define NO_SYS                          1
 #define SYS_LIGHTWEIGHT_PROT            1
 
 #define LWIP_SOCKET                     0
 #define LWIP_NETCONN                    0
 #define MEM_ALIGNMENT                   8
+#ifndef MEM_SIZE
 #define MEM_SIZE                        10485760
+#endif
 #define MEMP_NUM_PBUF                   131072
 #define MEMP_NUM_TCP_PCB                2048
 #define MEMP_NUM_TCP_PCB_LISTEN         2048
@@ -12,6 +20,25 @@
 // #define MEM_SANITY_CHECK                1
 // #define MEMP_SANITY_CHECK               1
 #define LWIP_IPV4                       1
+#ifndef LWIP_IPV6
+#define LWIP_IPV6                       1
+#endif
+// The tun device has no link layer, so no neighbour discovery chatter.
+#define LWIP_IPV6_MLD                   0
+#define LWIP_IPV6_AUTOCONFIG            0
+#define LWIP_IPV6_SEND_ROUTER_SOLICIT   0
+#define LWIP_IPV6_DUP_DETECT_ATTEMPTS   0
+#ifndef LWIP_UDP
+#define LWIP_UDP                        1
+#endif
+#ifndef LWIP_ICMP
+#define LWIP_ICMP                       1
+#endif
+#ifndef LWIP_STATS
+#define LWIP_STATS                      0
+#endif
+// Every UDP flow binds the original destination, which may be shared.
+#define SO_REUSE                        1
 
 // #define TCP_DEBUG                  LWIP_DBG_ON
 // #define TCP_INPUT_DEBUG            LWIP_DBG_ON
@@ -25,6 +52,8 @@
 
 
 #define TCP_LISTEN_BACKLOG              1
+// Ties every per-SYN listener to the connection it makes, see tun.c.
+#define LWIP_TCP_PCB_NUM_EXT_ARGS       1
 #define LWIP_DBG_MIN_LEVEL              LWIP_DBG_LEVEL_ALL
 
 #define TCP_MSS         1460
@@ -36,7 +65,12 @@
 #define LWIP_WND_SCALE  1
 #define TCP_QUEUE_OOSEQ 1
 #define TCP_SND_BUF     32768
+#ifndef LWIP_TCP_SACK_OUT
 #define LWIP_TCP_SACK_OUT 1
+#endif
+#ifndef LWIP_TCP_TIMESTAMPS
+#define LWIP_TCP_TIMESTAMPS 0
+#endif
 
 #define LWIP_TIMERS 1
 
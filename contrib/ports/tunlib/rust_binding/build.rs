@@ -1,5 +1,6 @@
 use std::env;
-use std::path::PathBuf;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 fn sdk_path(sdk: &str) -> String {
@@ -14,17 +15,103 @@ fn sdk_path(sdk: &str) -> String {
   stdout.trim().to_string()
 }
 
+fn feature(name: &str) -> bool {
+  let var = format!("CARGO_FEATURE_{}", name.to_uppercase().replace('-', "_"));
+  env::var_os(var).is_some()
+}
+
+// lwIP options for the cargo features. lwipopts.h includes them when
+// LWIP_TUN_FEATURES is defined, and falls back to its defaults otherwise.
+fn write_features_header(dir: &Path) {
+  let mut header = String::from("/* Generated by build.rs from the cargo features. */\n");
+
+  let options = [
+    ("LWIP_IPV6", feature("ipv6")),
+    ("LWIP_UDP", feature("udp")),
+    ("LWIP_ICMP", feature("icmp")),
+    ("LWIP_STATS", feature("stats")),
+    // 16 bit counters wrap around within seconds.
+    ("LWIP_STATS_LARGE", feature("stats")),
+    ("LWIP_TCP_SACK_OUT", feature("sack")),
+    ("LWIP_TCP_TIMESTAMPS", feature("timestamps")),
+  ];
+  for (option, enabled) in options {
+    writeln!(header, "#define {} {}", option, enabled as u8).unwrap();
+  }
+
+  println!("cargo:rerun-if-env-changed=LWIP_MEM_SIZE");
+  if let Ok(mem_size) = env::var("LWIP_MEM_SIZE") {
+    let mem_size: usize = mem_size.parse().expect("LWIP_MEM_SIZE is not a number");
+    writeln!(header, "#define MEM_SIZE {}", mem_size).unwrap();
+  }
+
+  if feature("lwip-debug") {
+    header.push_str("#define LWIP_DEBUG 1\n");
+    for option in ["IP_DEBUG", "IP6_DEBUG", "ICMP_DEBUG", "UDP_DEBUG", "TCP_DEBUG",
+                   "TCP_INPUT_DEBUG", "TCP_OUTPUT_DEBUG", "TCP_RST_DEBUG", "NETIF_DEBUG"] {
+      writeln!(header, "#define {} LWIP_DBG_ON", option).unwrap();
+    }
+  }
+
+  std::fs::write(dir.join("lwipopts_features.h"), header)
+    .expect("Couldn't write lwipopts_features.h");
+}
+
+fn c_files(dir: &Path) -> Vec<PathBuf> {
+  let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+    .unwrap_or_else(|err| panic!("Couldn't read {}: {}", dir.display(), err))
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| path.extension().map_or(false, |ext| ext == "c"))
+    .collect();
+  files.sort();
+  files
+}
+
+// Builds lwIP with the generated options, so it agrees with the bindings on
+// the layout of every struct. Whatever the options leave out compiles to
+// nothing.
+fn build_lwip(tunlib: &Path, features_dir: &Path) {
+  let lwip = tunlib.join("../../..");
+
+  let mut build = cc::Build::new();
+  for dir in ["src/core", "src/core/ipv4", "src/core/ipv6"] {
+    build.files(c_files(&lwip.join(dir)));
+  }
+
+  build
+    // netif_input() refers to it, even though a tun has no ethernet frames.
+    .file(lwip.join("src/netif/ethernet.c"))
+    .file(tunlib.join("sys_arch.c"))
+    .include(lwip.join("src/include"))
+    .include(lwip.join("contrib"))
+    .include(tunlib.join("include"))
+    .include(tunlib)
+    .include(features_dir)
+    .define("LWIP_TUN_FEATURES", None)
+    // lwIP's own warnings are none of our business.
+    .warnings(false)
+    .compile("lwip");
+}
+
 fn main() {
   let path = std::env::var("CARGO_MANIFEST_DIR").unwrap();
 
-  let cmake_output = PathBuf::new()
+  let tunlib = PathBuf::new()
     .join(path)
     .parent()
     .unwrap()
-    .join("build");
+    .to_path_buf();
 
+  let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+  write_features_header(&out_path);
 
   println!("cargo:rustc-rerun-if-changed=wrapper.h");
+  println!("cargo:rerun-if-changed=../lwipopts.h");
+  println!("cargo:rerun-if-changed=../sys_arch.c");
+  println!("cargo:rerun-if-changed=../include");
+  println!("cargo:rerun-if-changed=../../../../src");
+
+  build_lwip(&tunlib, &out_path);
 
   let mut builder = bindgen::Builder::default()
     // The input header we would like to generate
@@ -34,16 +121,13 @@ fn main() {
     .clang_arg("-I..")
     .clang_arg("-I/usr/include")
     .clang_arg("-I../../../../src/include")
+    .clang_arg(format!("-I{}", out_path.display()))
+    .clang_arg("-DLWIP_TUN_FEATURES")
     .allowlist_file(".*lwip.*")
     // Tell cargo to invalidate the built crate whenever any of the
     // included header files changed.
     .parse_callbacks(Box::new(bindgen::CargoCallbacks));
 
-  if cfg!(target_os = "linux") {
-    println!("cargo:rustc-link-search=native={}", cmake_output.display());
-    println!("cargo:rustc-link-lib=static=lwip");
-  }
-
   if cfg!(target_os = "macos") {
     let sdk = "macosx";
     builder = builder.clang_arg(format!("-isysroot{}", sdk_path(sdk)));
@@ -57,7 +141,6 @@ fn main() {
     .generate()
     .expect("Unable to generate bindings");
 
-  let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
   bindings
     .write_to_file(out_path.join("bindings.rs"))
     .expect("Couldn't write bindings!");
@@ -1,11 +1,12 @@
-use std::{fs::File, os::fd::{FromRawFd, AsRawFd}, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, io::{Read, Write}};
+use std::{os::fd::AsRawFd, net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4}};
 use log::debug;
 use simplelog::{SimpleLogger, LevelFilter, Config};
 use tokio::io::{AsyncWriteExt, AsyncReadExt};
-use tun::tun::TunNetif;
+use tun::linux::{TunDevice, TunOptions};
+use tun::tcp::Rejection;
+use tun::tun::{ConnectionInfo, TunNetif, UnreachableCode};
 
 extern "C" {
-    fn tun_open() -> i32;
     fn bind_eth0(socket: i32) -> i32;
 }
 
@@ -14,12 +15,17 @@ struct TcpHandler {
 }
 
 impl tun::tun::Pipe for TcpHandler {
-    fn handle_new_connection(&self, conn: tun::tcp::TcpConnection, dst: SocketAddr) {
-        println!("New Connection {:?}", dst);
+    fn handle_new_connection(&self, conn: tun::tcp::TcpConnection, info: ConnectionInfo) {
+        println!("New Connection {:?}", info);
+        let dst = info.dst;
         self.handle.spawn(async move {
             let mut tun_conn = conn;
 
-            let socket = tokio::net::TcpSocket::new_v4().unwrap();
+            let socket = if dst.is_ipv6() {
+                tokio::net::TcpSocket::new_v6().unwrap()
+            } else {
+                tokio::net::TcpSocket::new_v4().unwrap()
+            };
             // 198.19.249.150
             // socket.bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(198, 19, 249, 150), 0))).unwrap();
             // let socket.connect(dst).await
@@ -29,16 +35,28 @@ impl tun::tun::Pipe for TcpHandler {
             //     println!("Bind failed with error {}", res);
             // }
             // 198.19.249.150
-            socket.bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 100, 212), 0))).unwrap();
+            if dst.is_ipv4() {
+                socket.bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 100, 212), 0))).unwrap();
+            }
             let outbound_conn = socket.connect(dst).await;
 
             if let Err(e) = outbound_conn {
                 println!("Error connecting to {}: {:?}", dst, e);
+                let rejection = match e.kind() {
+                    std::io::ErrorKind::ConnectionRefused => Rejection::Reset,
+                    _ => Rejection::Unreachable(UnreachableCode::Host),
+                };
+                tun_conn.reject(rejection);
                 return;
             }
             let mut outbound_conn = outbound_conn.unwrap();
             println!("Connected to {}", dst);
 
+            if let Err(e) = tun_conn.accept() {
+                println!("Error accepting {}: {:?}", dst, e);
+                return;
+            }
+
             // let mut first_read_buf = [0u8; 15040];
 
             // let Ok(size) = tun_conn.read(&mut first_read_buf).await else {
@@ -85,36 +103,22 @@ fn main() {
         .build()
         .unwrap();
 
-    let fd = unsafe { tun_open() };
-    let mut file = unsafe {
-        File::from_raw_fd(fd)
-    };
     let ip = Ipv4Addr::new(192, 18, 0, 1);
     let netmask = Ipv4Addr::new(255, 255, 255, 0);
     let gateway = Ipv4Addr::new(192, 18, 0, 1);
     let handler = TcpHandler { handle: runtime.handle().clone() };
 
-    let mut tun = TunNetif::new(runtime.handle().clone(), ip, netmask, gateway, Box::new(handler));
-
-    println!("Opened tun device with fd {}", fd);
-
-    tun.set_output_fn(Box::new(move |data| {
-        // println!("Writing {} bytes to tun", data.len());
-        let mut file = unsafe { File::from_raw_fd(fd) };
-        let res = file.write_all(data);
-        if let Err(e) = res {
-            println!("Error writing to tun: {:?}", e);
-        }
-        std::mem::forget(file)
-    }));
-
-    loop {
-        // let time = std::time::Instant::now();
-        let mut buf = [0u8; 1504];
-        let len = file.read(&mut buf).unwrap();
-        // println!("Read {} bytes from tun", len);
-        tun.input_data(&buf[0..len]);
-        // let duration = time.elapsed();
-        // println!("Time elapsed is: {:?} speed: {}", duration, (len as f64 / duration.as_secs_f64()) / 1024f64);
-    }
+    let ip6 = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    let mut tun = TunNetif::new(ip, netmask, gateway, &[ip6], Box::new(handler));
+
+    tun.set_deferred_handshake(true);
+
+    runtime.block_on(async move {
+        let options = TunOptions { name: Some("tun1".to_string()), ..Default::default() };
+        let device = TunDevice::open(&options).unwrap();
+        println!("Opened tun device {}", device.name());
+
+        let res = tun.run(device).await;
+        println!("Tun device failed: {:?}", res);
+    });
 }
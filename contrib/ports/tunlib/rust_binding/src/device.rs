@@ -0,0 +1,212 @@
+use bytes::Bytes;
+use std::future::Future;
+use std::io;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Mutex};
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+#[cfg(target_os = "linux")]
+use tokio::io::unix::AsyncFd;
+
+/// Carries the IP packets of a `TunNetif`, see `TunNetif::run`.
+///
+/// Packets are received and sent at the same time, so both take `&self`.
+pub trait PacketDevice: Send + Sync {
+    /// Waits for the next packet and writes it to `buf`, returning its
+    /// length. An error ends `TunNetif::run`.
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Sends a single packet. An error ends `TunNetif::run`, so a packet
+    /// that is lost on the way isn't one.
+    fn send(&self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// The largest packet the device carries.
+    fn mtu(&self) -> u16;
+}
+
+/// A Linux TUN device opened with `IFF_TUN | IFF_NO_PI`, so every read and
+/// write is a single packet.
+#[cfg(target_os = "linux")]
+pub struct TunFd {
+    fd: AsyncFd<OwnedFd>,
+    mtu: u16,
+}
+
+#[cfg(target_os = "linux")]
+impl TunFd {
+    /// Takes over `fd` and makes it non-blocking. Needs a tokio runtime with
+    /// the IO driver.
+    pub fn new(fd: OwnedFd, mtu: u16) -> io::Result<TunFd> {
+        set_nonblocking(fd.as_raw_fd())?;
+        Ok(TunFd { fd: AsyncFd::new(fd)?, mtu })
+    }
+
+    /// Receives a single packet.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_vectored(&mut [io::IoSliceMut::new(buf)]).await
+    }
+
+    /// Receives a single packet, spread over `bufs` in order.
+    pub async fn read_vectored(&self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                // `IoSliceMut` is ABI compatible with `iovec`.
+                let len = unsafe {
+                    libc::readv(fd.as_raw_fd(), bufs.as_ptr().cast(), bufs.len() as libc::c_int)
+                };
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(len as usize)
+            });
+            match result {
+                Err(_would_block) => continue,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(result) => return result,
+            }
+        }
+    }
+
+    /// Sends a single packet.
+    pub async fn write(&self, packet: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[io::IoSlice::new(packet)]).await
+    }
+
+    /// Sends a single packet, made of `bufs` in order.
+    pub async fn write_vectored(&self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.writable().await?;
+            let result = guard.try_io(|fd| {
+                let len = unsafe {
+                    libc::writev(fd.as_raw_fd(), bufs.as_ptr().cast(), bufs.len() as libc::c_int)
+                };
+                if len < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(len as usize)
+            });
+            match result {
+                Err(_would_block) => continue,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(result) => return result,
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PacketDevice for TunFd {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read(buf).await? {
+            // Only a socket standing in for the device reads nothing, once
+            // the other end is closed.
+            0 if !buf.is_empty() => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            len => Ok(len),
+        }
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.write(packet).await.map(drop)
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Raw IP packets in the datagrams of a connected UDP socket, like a
+/// userspace VPN sends them once decrypted.
+pub struct UdpDevice {
+    socket: UdpSocket,
+    mtu: u16,
+}
+
+impl UdpDevice {
+    /// `socket` must be connected to the peer.
+    pub fn new(socket: UdpSocket, mtu: u16) -> UdpDevice {
+        UdpDevice { socket, mtu }
+    }
+}
+
+impl PacketDevice for UdpDevice {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.socket.recv(buf).await {
+                // An ICMP error for an earlier datagram, the peer may be back.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                result => return result,
+            }
+        }
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self.socket.send(packet).await {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            result => result.map(drop),
+        }
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+}
+
+/// One end of an in-memory link, see `ChannelDevice::pair`.
+pub struct ChannelDevice {
+    sender: mpsc::Sender<Bytes>,
+    receiver: Mutex<mpsc::Receiver<Bytes>>,
+    mtu: u16,
+}
+
+impl ChannelDevice {
+    /// Two devices linked to each other, with up to `capacity` packets on
+    /// the way in either direction. Handy to put two netifs back to back in
+    /// tests, or to drive one by hand.
+    pub fn pair(capacity: usize, mtu: u16) -> (ChannelDevice, ChannelDevice) {
+        let (a_sender, a_receiver) = mpsc::channel(capacity);
+        let (b_sender, b_receiver) = mpsc::channel(capacity);
+        (
+            ChannelDevice { sender: a_sender, receiver: Mutex::new(b_receiver), mtu },
+            ChannelDevice { sender: b_sender, receiver: Mutex::new(a_receiver), mtu },
+        )
+    }
+}
+
+impl PacketDevice for ChannelDevice {
+    /// A packet longer than `buf` is cut off, like a datagram.
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .receiver
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.sender
+            .send(Bytes::copy_from_slice(packet))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+}
@@ -0,0 +1,214 @@
+use crate::lwip_binding::{sys_check_timeouts, sys_timeouts_sleeptime, SYS_TIMEOUTS_SLEEPTIME_INFINITE};
+use crate::tun::TimerPolicy;
+use log::error;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Jobs run before the deferred ones get their turn.
+const MAX_BATCH: usize = 256;
+
+/// The one thread lwIP runs on.
+///
+/// Everything touching the stack is queued to it as a job. The thread runs
+/// whatever queued up in one batch, then the work deferred with
+/// `at_batch_end`, then lwIP's timers that are due. Then it waits for more,
+/// but no longer than until the timers are due again, see `TimerPolicy`.
+pub(crate) struct EventLoop {
+    sender: Sender<Job>,
+    thread: ThreadId,
+}
+
+thread_local! {
+    static BATCH_END: RefCell<Vec<Box<dyn FnOnce()>>> = RefCell::new(Vec::new());
+    // What every netif asked for, the most demanding one wins.
+    static TIMER_POLICIES: RefCell<Vec<TimerPolicy>> = RefCell::new(Vec::new());
+    // For `TimerPolicy::Interval`.
+    static LAST_TIMER_CHECK: Cell<Option<Instant>> = Cell::new(None);
+}
+
+impl EventLoop {
+    /// The lwIP thread, started on first use. lwIP only has global state,
+    /// so every `TunNetif` shares it.
+    pub(crate) fn get() -> &'static EventLoop {
+        EventLoop::start(None)
+    }
+
+    /// Like `get`, naming the thread if it isn't running yet.
+    pub(crate) fn start(thread_name: Option<&str>) -> &'static EventLoop {
+        static EVENT_LOOP: OnceLock<EventLoop> = OnceLock::new();
+        EVENT_LOOP.get_or_init(|| EventLoop::spawn(thread_name.unwrap_or("lwip")))
+    }
+
+    fn spawn(thread_name: &str) -> EventLoop {
+        let (sender, receiver) = mpsc::channel();
+
+        let handle = std::thread::Builder::new()
+            .name(thread_name.to_string())
+            .spawn(move || {
+                unsafe { crate::lwip_binding::lwip_init() };
+                run_loop(receiver);
+            })
+            .expect("failed to spawn the lwIP thread");
+
+        EventLoop {
+            sender,
+            thread: handle.thread().id(),
+        }
+    }
+
+    pub(crate) fn is_current(&self) -> bool {
+        std::thread::current().id() == self.thread
+    }
+
+    /// Queues `job` without waiting for it.
+    pub(crate) fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        // The thread never exits, so this can't fail.
+        let _ = self.sender.send(Box::new(job));
+    }
+
+    /// Runs `job` on the lwIP thread and waits for its result. Runs it right
+    /// away when called from the lwIP thread.
+    ///
+    /// This blocks the calling thread, so it is only meant for setting
+    /// things up and for callers that aren't async, see `run_async`.
+    pub(crate) fn run<R: Send>(&self, job: impl FnOnce() -> R + Send) -> R {
+        if self.is_current() {
+            return job();
+        }
+
+        let (done_sender, done_receiver) = mpsc::sync_channel::<Result<R, Box<dyn Any + Send>>>(1);
+
+        let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+            let result = std::panic::catch_unwind(AssertUnwindSafe(job));
+            let _ = done_sender.send(result);
+        });
+        // SAFETY: nothing the job borrows goes away before it ran or was
+        // dropped, as `done_receiver` waits for either.
+        let job: Job = unsafe { std::mem::transmute(job) };
+        let _ = self.sender.send(job);
+
+        match done_receiver.recv() {
+            Ok(Ok(result)) => result,
+            Ok(Err(panic)) => std::panic::resume_unwind(panic),
+            Err(_) => panic!("the lwIP thread is gone"),
+        }
+    }
+
+    /// Like `run`, but waits for the result without blocking the caller.
+    pub(crate) async fn run_async<R: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> R + Send + 'static,
+    ) -> R {
+        let (done_sender, done_receiver) = tokio::sync::oneshot::channel();
+
+        self.execute(move || {
+            let _ = done_sender.send(job());
+        });
+
+        done_receiver.await.expect("a job on the lwIP thread panicked")
+    }
+
+    /// Defers `job` until everything queued so far ran. Only for the lwIP
+    /// thread, where it lets a batch of writes go out with one `tcp_output`.
+    pub(crate) fn at_batch_end(&self, job: impl FnOnce() + 'static) {
+        debug_assert!(self.is_current());
+        BATCH_END.with(|batch_end| batch_end.borrow_mut().push(Box::new(job)));
+    }
+
+    /// Runs lwIP's timers for a new netif, on the lwIP thread.
+    pub(crate) fn hold_timers(&self, policy: TimerPolicy) {
+        debug_assert!(self.is_current());
+        TIMER_POLICIES.with(|policies| policies.borrow_mut().push(policy));
+    }
+
+    /// Stops lwIP's timers once no netif is left, on the lwIP thread.
+    pub(crate) fn release_timers(&self, policy: TimerPolicy) {
+        debug_assert!(self.is_current());
+        TIMER_POLICIES.with(|policies| {
+            let mut policies = policies.borrow_mut();
+            if let Some(index) = policies.iter().position(|held| *held == policy) {
+                policies.swap_remove(index);
+            }
+        });
+    }
+}
+
+fn run_loop(receiver: Receiver<Job>) {
+    loop {
+        let job = match next_timeout() {
+            None => match receiver.recv() {
+                Ok(job) => Some(job),
+                Err(_) => break,
+            },
+            Some(timeout) => match receiver.recv_timeout(timeout) {
+                Ok(job) => Some(job),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+        };
+
+        if let Some(job) = job {
+            run_batch(&receiver, job);
+        }
+
+        if next_timeout() == Some(Duration::ZERO) {
+            LAST_TIMER_CHECK.with(|last| last.set(Some(Instant::now())));
+            unsafe { sys_check_timeouts() };
+        }
+    }
+}
+
+// The policy all netifs get along with, `None` without netifs.
+fn timer_policy() -> Option<TimerPolicy> {
+    TIMER_POLICIES.with(|policies| {
+        policies.borrow().iter().copied().min_by_key(|policy| match policy {
+            TimerPolicy::Deadline => Duration::ZERO,
+            TimerPolicy::Interval(interval) => *interval,
+        })
+    })
+}
+
+// How long until lwIP's timers are to be checked, `None` for never.
+fn next_timeout() -> Option<Duration> {
+    match timer_policy()? {
+        TimerPolicy::Deadline => match unsafe { sys_timeouts_sleeptime() } {
+            SYS_TIMEOUTS_SLEEPTIME_INFINITE => None,
+            millis => Some(Duration::from_millis(u64::from(millis))),
+        },
+        TimerPolicy::Interval(interval) => {
+            let elapsed = LAST_TIMER_CHECK.with(Cell::get).map_or(interval, |last| last.elapsed());
+            Some(interval.saturating_sub(elapsed))
+        }
+    }
+}
+
+fn run_batch(receiver: &Receiver<Job>, job: Job) {
+    run_job(job);
+    for job in receiver.try_iter().take(MAX_BATCH - 1) {
+        run_job(job);
+    }
+
+    loop {
+        let deferred = BATCH_END.with(|batch_end| std::mem::take(&mut *batch_end.borrow_mut()));
+        if deferred.is_empty() {
+            break;
+        }
+        for job in deferred {
+            run_job(job);
+        }
+    }
+}
+
+fn run_job(job: impl FnOnce()) {
+    // A panicking job must not take the stack down for everybody else.
+    if std::panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        error!("a job on the lwIP thread panicked");
+    }
+}
@@ -1,3 +1,12 @@
+mod event_loop;
 mod lwip_binding;
 pub mod tun;
+pub mod packet;
 pub mod tcp;
+pub mod device;
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(feature = "udp")]
+pub mod udp;
+#[cfg(feature = "stats")]
+pub mod stats;
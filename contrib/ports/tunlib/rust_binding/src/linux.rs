@@ -0,0 +1,183 @@
+use crate::device::{PacketDevice, TunFd};
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+// `struct tun_pi`, in front of every packet without `IFF_NO_PI`.
+const PACKET_INFO_LEN: usize = 4;
+// Room for the packet information and the largest `virtio_net_hdr` we take.
+const MAX_HEADER_LEN: usize = 64;
+
+/// How `TunDevice::open` sets up the interface.
+#[derive(Debug, Clone, Default)]
+pub struct TunOptions {
+    /// The name of the interface, or a pattern like `tun%d`. The kernel
+    /// picks one if `None`.
+    pub name: Option<String>,
+    /// Turns off `IFF_NO_PI`, so the kernel puts a `struct tun_pi` in front
+    /// of every packet. The device takes care of it either way.
+    pub packet_info: bool,
+    /// `IFF_VNET_HDR`, a `virtio_net_hdr` in front of every packet. Without
+    /// offloads it carries nothing, the device takes care of it.
+    pub vnet_hdr: bool,
+    /// `IFF_MULTI_QUEUE`, so the interface can be opened several times with
+    /// one queue each.
+    pub multi_queue: bool,
+}
+
+/// A Linux TUN interface, driven without blocking on the tokio runtime.
+pub struct TunDevice {
+    fd: TunFd,
+    name: String,
+    packet_info: bool,
+    vnet_hdr_len: usize,
+}
+
+impl TunDevice {
+    /// Creates the interface, or attaches to it if it exists already, which
+    /// needs `CAP_NET_ADMIN`. Needs a tokio runtime with the IO driver.
+    pub fn open(options: &TunOptions) -> io::Result<TunDevice> {
+        let fd = unsafe { libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut flags = libc::IFF_TUN;
+        if !options.packet_info {
+            flags |= libc::IFF_NO_PI;
+        }
+        if options.vnet_hdr {
+            flags |= libc::IFF_VNET_HDR;
+        }
+        if options.multi_queue {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+
+        let mut ifr = ifreq(options.name.as_deref().unwrap_or(""))?;
+        ifr.ifr_ifru.ifru_flags = flags as libc::c_short;
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &ifr) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        TunDevice::from_fd(fd)
+    }
+
+    /// Takes over a TUN interface opened elsewhere, like one inherited from
+    /// systemd or passed over a UNIX socket. How it was set up is read back
+    /// from the kernel. Needs a tokio runtime with the IO driver.
+    pub fn from_fd(fd: OwnedFd) -> io::Result<TunDevice> {
+        let mut ifr = ifreq("")?;
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNGETIFF, &mut ifr) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = libc::c_int::from(unsafe { ifr.ifr_ifru.ifru_flags });
+        if flags & libc::IFF_TUN == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a TUN interface"));
+        }
+        let name = unsafe { CStr::from_ptr(ifr.ifr_name.as_ptr()) }.to_string_lossy().into_owned();
+
+        let mut vnet_hdr_len: libc::c_int = 0;
+        if flags & libc::IFF_VNET_HDR != 0
+            && unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNGETVNETHDRSZ, &mut vnet_hdr_len) } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let vnet_hdr_len = vnet_hdr_len as usize;
+        if PACKET_INFO_LEN + vnet_hdr_len > MAX_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "virtio_net_hdr too large"));
+        }
+
+        let mtu = interface_mtu(&name)?;
+        Ok(TunDevice {
+            fd: TunFd::new(fd, mtu)?,
+            name,
+            packet_info: flags & libc::IFF_NO_PI == 0,
+            vnet_hdr_len,
+        })
+    }
+
+    /// Like `from_fd`, for a raw fd this takes over.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be open and owned by nothing else.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<TunDevice> {
+        TunDevice::from_fd(OwnedFd::from_raw_fd(fd))
+    }
+
+    /// The name of the interface, like `tun0`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // What the kernel puts in front of every packet.
+    fn header_len(&self) -> usize {
+        let packet_info_len = if self.packet_info { PACKET_INFO_LEN } else { 0 };
+        packet_info_len + self.vnet_hdr_len
+    }
+}
+
+impl PacketDevice for TunDevice {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let header_len = self.header_len();
+        if header_len == 0 {
+            return self.fd.read(buf).await;
+        }
+
+        let mut header = [0; MAX_HEADER_LEN];
+        let mut bufs = [io::IoSliceMut::new(&mut header[..header_len]), io::IoSliceMut::new(buf)];
+        let len = self.fd.read_vectored(&mut bufs).await?;
+        Ok(len.saturating_sub(header_len))
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let header_len = self.header_len();
+        if header_len == 0 {
+            return self.fd.write(packet).await.map(drop);
+        }
+
+        // No flags, and a `virtio_net_hdr` of zeroes, which is no offloads.
+        let mut header = [0; MAX_HEADER_LEN];
+        if self.packet_info {
+            let proto = match packet.first().map(|b| b >> 4) {
+                Some(6) => libc::ETH_P_IPV6,
+                _ => libc::ETH_P_IP,
+            };
+            header[2..4].copy_from_slice(&(proto as u16).to_be_bytes());
+        }
+        let bufs = [io::IoSlice::new(&header[..header_len]), io::IoSlice::new(packet)];
+        self.fd.write_vectored(&bufs).await.map(drop)
+    }
+
+    fn mtu(&self) -> u16 {
+        self.fd.mtu()
+    }
+}
+
+// A zeroed request for the interface `name`.
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+    if name.len() >= ifr.ifr_name.len() || name.contains('\0') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"));
+    }
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(ifr)
+}
+
+fn interface_mtu(name: &str) -> io::Result<u16> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+    let mut ifr = ifreq(name)?;
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFMTU as _, &mut ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mtu = unsafe { ifr.ifr_ifru.ifru_mtu };
+    Ok(u16::try_from(mtu).unwrap_or(u16::MAX))
+}
@@ -0,0 +1,125 @@
+use crate::event_loop::EventLoop;
+use crate::lwip_binding::{pbuf, pbuf_free, pbuf_ref, pbuf_type, pbuf_type_PBUF_REF};
+use crate::tun::PtrWrapper;
+use bytes::Bytes;
+use std::borrow::Cow;
+use std::io::IoSlice;
+
+/// A packet the stack sends out, see `TunNetif::set_packet_output_fn`.
+///
+/// lwIP often builds a packet as a chain, like the headers in one pbuf and
+/// the payload in the buffer the application wrote. The packet holds a
+/// reference on the chain, so it can be written later without copying it:
+/// lwIP doesn't retransmit a segment it still refers to. The payload in the
+/// application's buffer is copied though, the connection lets go of that
+/// buffer once it is acked or the connection is gone.
+pub struct Packet {
+    pbuf: *mut pbuf,
+    // Taken on the lwIP thread, which moves the payload pointers around
+    // for retransmissions later.
+    pieces: Vec<Piece>,
+}
+
+enum Piece {
+    Pbuf(*const u8, usize),
+    // The payload of a `PBUF_REF`.
+    Copy(Box<[u8]>),
+}
+
+impl Piece {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Piece::Pbuf(data, len) => unsafe { std::slice::from_raw_parts(*data, *len) },
+            Piece::Copy(copy) => copy,
+        }
+    }
+}
+
+unsafe impl Send for Packet {}
+unsafe impl Sync for Packet {}
+
+impl Packet {
+    /// Takes a reference on the chain `pbuf` starts, on the lwIP thread.
+    pub(crate) unsafe fn new(pbuf: *mut pbuf) -> Packet {
+        pbuf_ref(pbuf);
+
+        let mut pieces = Vec::new();
+        let mut left = usize::from((*pbuf).tot_len);
+        let mut next = pbuf;
+        // Whatever follows the packet's last pbuf isn't part of it.
+        while let Some(current) = next.as_ref().filter(|_| left > 0) {
+            let len = usize::from(current.len).min(left);
+            if len > 0 {
+                let data = current.payload as *const u8;
+                if pbuf_type::from(current.type_internal) == pbuf_type_PBUF_REF {
+                    pieces.push(Piece::Copy(std::slice::from_raw_parts(data, len).into()));
+                } else {
+                    pieces.push(Piece::Pbuf(data, len));
+                }
+            }
+            left -= len;
+            next = current.next;
+        }
+
+        Packet { pbuf, pieces }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks().map(<[u8]>::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    /// The pieces of the packet, in order.
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.pieces.iter().map(Piece::as_slice)
+    }
+
+    /// The pieces of the packet, for a vectored write.
+    pub fn io_slices(&self) -> Vec<IoSlice<'_>> {
+        self.chunks().map(IoSlice::new).collect()
+    }
+
+    /// The packet in one buffer, only copied if it is a chain.
+    pub fn contiguous(&self) -> Cow<'_, [u8]> {
+        match self.pieces.as_slice() {
+            [] => Cow::Borrowed(&[]),
+            [piece] => Cow::Borrowed(piece.as_slice()),
+            _ => Cow::Owned(self.chunks().collect::<Vec<_>>().concat()),
+        }
+    }
+
+    /// Like `contiguous`, but keeps a single pbuf referenced instead of
+    /// copying it.
+    pub fn into_bytes(self) -> Bytes {
+        if self.pieces.len() == 1 {
+            return Bytes::from_owner(self);
+        }
+        Bytes::from(self.contiguous().into_owned())
+    }
+}
+
+// Only used for packets of a single pbuf, see `into_bytes`.
+impl AsRef<[u8]> for Packet {
+    fn as_ref(&self) -> &[u8] {
+        self.chunks().next().unwrap_or(&[])
+    }
+}
+
+impl Drop for Packet {
+    fn drop(&mut self) {
+        let event_loop = EventLoop::get();
+        if event_loop.is_current() {
+            unsafe { pbuf_free(self.pbuf) };
+            return;
+        }
+
+        let pbuf_wrapper = PtrWrapper(self.pbuf);
+        event_loop.execute(move || unsafe {
+            let pbuf_wrapper = pbuf_wrapper;
+            pbuf_free(pbuf_wrapper.0);
+        });
+    }
+}
@@ -0,0 +1,97 @@
+use crate::event_loop::EventLoop;
+use crate::lwip_binding::{lwip_stats, stats_mem, stats_proto};
+
+/// Counters of one protocol, lwIP's `struct stats_proto`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtoStats {
+    pub xmit: u32,
+    pub recv: u32,
+    pub fw: u32,
+    pub drop: u32,
+    pub chkerr: u32,
+    pub lenerr: u32,
+    pub memerr: u32,
+    pub rterr: u32,
+    pub proterr: u32,
+    pub opterr: u32,
+    pub err: u32,
+    pub cachehit: u32,
+}
+
+impl From<&stats_proto> for ProtoStats {
+    fn from(stats: &stats_proto) -> Self {
+        ProtoStats {
+            xmit: stats.xmit.into(),
+            recv: stats.recv.into(),
+            fw: stats.fw.into(),
+            drop: stats.drop.into(),
+            chkerr: stats.chkerr.into(),
+            lenerr: stats.lenerr.into(),
+            memerr: stats.memerr.into(),
+            rterr: stats.rterr.into(),
+            proterr: stats.proterr.into(),
+            opterr: stats.opterr.into(),
+            err: stats.err.into(),
+            cachehit: stats.cachehit.into(),
+        }
+    }
+}
+
+/// Usage of lwIP's heap, which holds the segments waiting to be sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemStats {
+    pub avail: usize,
+    pub used: usize,
+    /// The most ever used at once.
+    pub max: usize,
+    /// Allocations that failed.
+    pub err: u32,
+}
+
+impl From<&stats_mem> for MemStats {
+    fn from(stats: &stats_mem) -> Self {
+        MemStats {
+            avail: stats.avail as usize,
+            used: stats.used as usize,
+            max: stats.max as usize,
+            err: stats.err.into(),
+        }
+    }
+}
+
+/// The counters of the whole stack, which all netifs share.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub ip: ProtoStats,
+    #[cfg(feature = "icmp")]
+    pub icmp: ProtoStats,
+    #[cfg(feature = "ipv6")]
+    pub ip6: ProtoStats,
+    #[cfg(feature = "ipv6")]
+    pub icmp6: ProtoStats,
+    pub tcp: ProtoStats,
+    #[cfg(feature = "udp")]
+    pub udp: ProtoStats,
+    pub mem: MemStats,
+}
+
+/// Takes a consistent snapshot of the counters on the lwIP thread.
+pub fn snapshot() -> Stats {
+    EventLoop::get().run(|| unsafe {
+        let stats = &*std::ptr::addr_of!(lwip_stats);
+
+        Stats {
+            ip: (&stats.ip).into(),
+            #[cfg(feature = "icmp")]
+            icmp: (&stats.icmp).into(),
+            #[cfg(feature = "ipv6")]
+            ip6: (&stats.ip6).into(),
+            #[cfg(feature = "ipv6")]
+            icmp6: (&stats.icmp6).into(),
+            tcp: (&stats.tcp).into(),
+            #[cfg(feature = "udp")]
+            udp: (&stats.udp).into(),
+            mem: (&stats.mem).into(),
+        }
+    })
+}
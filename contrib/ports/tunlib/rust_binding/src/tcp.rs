@@ -1,60 +1,482 @@
+use crate::event_loop::EventLoop;
 use crate::lwip_binding::{
-    err_enum_t_ERR_OK, err_t, pbuf, pbuf_free, tcp_arg, tcp_close, tcp_output, tcp_pcb, tcp_recv,
-    tcp_write, TCP_WRITE_FLAG_COPY, err_enum_t_ERR_MEM, tcp_poll, err_enum_t_ERR_CONN, err_enum_t_ERR_USE, err_enum_t_ERR_ABRT, err_enum_t_ERR_RST, tcp_state_CLOSED, tcp_state_CLOSE_WAIT, tcp_state_CLOSING, tcp_recved, tcp_sent, tcp_state, tcp_state_FIN_WAIT_1, tcp_state_FIN_WAIT_2, tcp_abort, tcp_err, tcp_state_TIME_WAIT, tcp_state_LISTEN, tcp_state_SYN_SENT,
+    err_enum_t_ERR_OK, err_t, pbuf, pbuf_free, tcp_arg, tcp_output, tcp_pcb, tcp_recv,
+    tcp_write, TCP_WRITE_FLAG_MORE, err_enum_t_ERR_MEM, tcp_poll, err_enum_t_ERR_CONN, err_enum_t_ERR_USE, err_enum_t_ERR_ABRT, err_enum_t_ERR_RST, tcp_state_CLOSED, tcp_recved, tcp_sent, tcp_abort, tcp_err, tcp_state_LISTEN, tcp_state_SYN_SENT,
+    tun_tcp_accept_pending, tcp_shutdown, err_enum_t_ERR_CLSD,
+    err_enum_t_ERR_TIMEOUT, SOF_KEEPALIVE, TF_NODELAY, tcp_seg, tcp_state, tcp_state_SYN_RCVD,
+    tcp_state_ESTABLISHED, tcp_state_FIN_WAIT_1, tcp_state_FIN_WAIT_2, tcp_state_CLOSE_WAIT,
+    tcp_state_CLOSING, tcp_state_LAST_ACK, tcp_state_TIME_WAIT,
 };
-use crate::tun::PtrWrapper;
+#[cfg(feature = "icmp")]
+use crate::lwip_binding::{
+    netif, netif_input, pbuf_alloc, pbuf_layer_PBUF_RAW, pbuf_take, pbuf_type_PBUF_POOL,
+    tcp_abandon,
+};
+use crate::tun::{socket_addr_from_lwip, PtrWrapper};
+#[cfg(feature = "icmp")]
+use crate::tun::{Rejections, UnreachableCode};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use core::task::{Context, Poll};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use log::debug;
-use rayon::ThreadPool;
 use std::ffi::c_void;
 use std::pin::Pin;
-use std::{io::Result, task::Waker};
+use std::time::{Duration, Instant};
+use std::{io::IoSlice, io::Result, task::Waker};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 pub struct TcpConnection {
-    pcb: *mut tcp_pcb,
+    event_loop: &'static EventLoop,
+
+    callback: Arc<Mutex<Callback>>,
+
+    pending: Option<PendingSyn>,
+
+    connections: Arc<Connections>,
+    id: ConnectionId,
+}
+
+/// Tells the connections of a netif apart, see `TunNetif::connections`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// lwIP's state of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl From<tcp_state> for TcpState {
+    #[allow(non_upper_case_globals)]
+    fn from(state: tcp_state) -> Self {
+        match state {
+            tcp_state_LISTEN => TcpState::Listen,
+            tcp_state_SYN_SENT => TcpState::SynSent,
+            tcp_state_SYN_RCVD => TcpState::SynReceived,
+            tcp_state_ESTABLISHED => TcpState::Established,
+            tcp_state_FIN_WAIT_1 => TcpState::FinWait1,
+            tcp_state_FIN_WAIT_2 => TcpState::FinWait2,
+            tcp_state_CLOSE_WAIT => TcpState::CloseWait,
+            tcp_state_CLOSING => TcpState::Closing,
+            tcp_state_LAST_ACK => TcpState::LastAck,
+            tcp_state_TIME_WAIT => TcpState::TimeWait,
+            _ => TcpState::Closed,
+        }
+    }
+}
+
+/// What a connection looked like when `TunNetif::connections` was called.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSnapshot {
+    pub id: ConnectionId,
+    /// The tun client's address.
+    pub src: SocketAddr,
+    /// The address the client connected to.
+    pub dst: SocketAddr,
+    /// `TcpState::Closed` once the stack let go of the connection.
+    pub state: TcpState,
+    /// Received from the client.
+    pub bytes_in: u64,
+    /// Acknowledged by the client.
+    pub bytes_out: u64,
+    pub age: Duration,
+    /// Since the last segment that carried data either way.
+    pub idle: Duration,
+    /// Written by the application but not sent yet.
+    pub unsent: usize,
+}
+
+/// The transport metrics of a connection as lwIP sees them, like Linux'
+/// `TCP_INFO`, see `TcpConnection::info`.
+///
+/// lwIP measures the RTT in ticks of its slow timer, so `rtt`, `rtt_var` and
+/// `rto` come in steps of 500 ms.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpInfo {
+    pub state: TcpState,
+    /// Smoothed round trip time, lwIP's `sa`.
+    pub rtt: Duration,
+    /// Round trip time variance, lwIP's `sv`.
+    pub rtt_var: Duration,
+    /// Retransmission timeout, backoff included.
+    pub rto: Duration,
+    /// Retransmissions of the oldest unacknowledged segment so far.
+    pub retransmits: u8,
+    pub mss: u16,
+    /// Congestion window, in bytes.
+    pub cwnd: u32,
+    /// Slow start threshold, in bytes.
+    pub ssthresh: u32,
+    /// What the client's last window allows to send, in bytes.
+    pub send_window: u32,
+    /// What we announce to the client, in bytes.
+    pub recv_window: u32,
+    /// Sent but not acknowledged yet.
+    pub unacked: usize,
+    /// Handed to lwIP, but not sent yet.
+    pub unsent: usize,
+    /// Written by the application, but not handed to lwIP yet.
+    pub queued: usize,
+}
+
+// lwIP's TCP_SLOW_INTERVAL, the tick of its RTT estimates.
+const SLOW_TIMER_INTERVAL: Duration = Duration::from_millis(500);
 
-    pcb_freed: bool,
+fn slow_ticks(ticks: i32) -> Duration {
+    SLOW_TIMER_INTERVAL * ticks.max(0) as u32
+}
 
-    pool: std::sync::Arc<ThreadPool>,
+unsafe fn segments_len(mut seg: *const tcp_seg) -> usize {
+    let mut len = 0;
+    while let Some(current) = seg.as_ref() {
+        len += usize::from(current.len);
+        seg = current.next;
+    }
+    len
+}
 
-    callback: Pin<Box<Mutex<Callback>>>,
+/// The connections a netif handed out that are still around.
+#[derive(Default)]
+pub(crate) struct Connections {
+    next_id: AtomicU64,
+    live: Mutex<HashMap<ConnectionId, Weak<Mutex<Callback>>>>,
+}
+
+impl Connections {
+    pub(crate) fn len(&self) -> usize {
+        self.live.lock().unwrap().len()
+    }
+
+    fn insert(&self, callback: &Arc<Mutex<Callback>>) -> ConnectionId {
+        let id = ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.live.lock().unwrap().insert(id, Arc::downgrade(callback));
+        id
+    }
+
+    fn remove(&self, id: ConnectionId) {
+        self.live.lock().unwrap().remove(&id);
+    }
+
+    // Without holding the table's lock while the callbacks are locked.
+    fn callbacks(&self) -> Vec<(ConnectionId, Arc<Mutex<Callback>>)> {
+        self.live
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(id, callback)| Some((*id, callback.upgrade()?)))
+            .collect()
+    }
+
+    /// On the lwIP thread, sorted by id.
+    pub(crate) unsafe fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        let now = Instant::now();
+
+        let mut snapshot: Vec<_> = self
+            .callbacks()
+            .into_iter()
+            .map(|(id, callback)| {
+                let locked = callback.lock().unwrap();
+
+                let (state, unsent_in_pcb) = if locked.pcb_alive() {
+                    let pcb = locked.pcb;
+                    ((*pcb).state.into(), (*pcb).snd_lbb.wrapping_sub((*pcb).snd_nxt) as usize)
+                } else {
+                    (TcpState::Closed, 0)
+                };
+
+                ConnectionSnapshot {
+                    id,
+                    src: locked.src,
+                    dst: locked.dst,
+                    state,
+                    bytes_in: locked.bytes_in,
+                    bytes_out: locked.bytes_out,
+                    age: now.duration_since(locked.created),
+                    idle: now.duration_since(locked.last_activity),
+                    unsent: locked.queued_len + unsent_in_pcb,
+                }
+            })
+            .collect();
+
+        snapshot.sort_by_key(|connection| connection.id);
+        snapshot
+    }
+
+    /// On the lwIP thread: aborts the connection, if it is still open.
+    pub(crate) unsafe fn kill(&self, id: ConnectionId) -> bool {
+        let Some(callback) = self.live.lock().unwrap().get(&id).and_then(Weak::upgrade) else {
+            return false;
+        };
+
+        let locked = callback.lock().unwrap();
+        if !locked.pcb_alive() {
+            return false;
+        }
+
+        let pcb = locked.pcb;
+        drop(locked);
+        // Calls `err_function`, which takes the lock.
+        tcp_abort(pcb);
+        true
+    }
+
+    /// On the lwIP thread: has every open connection send its FIN once what
+    /// was written to it is sent, and resets the pending ones.
+    pub(crate) unsafe fn shutdown_all(&self) {
+        for (_, callback) in self.callbacks() {
+            let mut locked = callback.lock().unwrap();
+            if !locked.pcb_alive() {
+                continue;
+            }
+
+            let pcb = locked.pcb;
+            if (*pcb).state == tcp_state_SYN_RCVD {
+                // Not even the SYN|ACK went out, so there is nothing to close.
+                drop(locked);
+                tcp_abort(pcb);
+                continue;
+            }
+
+            locked.shutdown_requested = true;
+            drop(locked);
+            pump(&callback);
+        }
+    }
+}
+
+/// TCP options every connection of a netif starts with, see
+/// `TunNetifBuilder::tcp_options`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// Disables Nagle's algorithm.
+    pub nodelay: bool,
+    /// Sends keepalive probes after the connection was idle this long.
+    pub keepalive: Option<Duration>,
+    /// TTL, or hop limit for IPv6, of the segments sent to the client.
+    pub ttl: Option<u8>,
+}
+
+/// What a connection handed out before its handshake needs to reject it.
+#[cfg(feature = "icmp")]
+pub(crate) struct PendingSyn {
+    pub(crate) syn: Vec<u8>,
+    pub(crate) netif: *mut netif,
+    pub(crate) src: SocketAddr,
+    pub(crate) dst: SocketAddr,
+    pub(crate) rejections: Rejections,
+}
+
+/// Without ICMP a pending connection can only be reset.
+#[cfg(not(feature = "icmp"))]
+pub(crate) struct PendingSyn;
+
+/// How to answer the SYN of a pending connection, see `TcpConnection::reject`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Answer with a RST, like a closed port.
+    Reset,
+    /// Answer with an ICMP destination unreachable.
+    #[cfg(feature = "icmp")]
+    Unreachable(UnreachableCode),
 }
 
 unsafe impl Send for TcpConnection {}
 unsafe impl Sync for TcpConnection {}
 
 struct Callback {
+    pcb: *mut tcp_pcb,
+    // For the received pbufs, which may outlive the connection.
+    this: Weak<Mutex<Callback>>,
     recv_waker: Option<Waker>,
     write_waker: Option<Waker>,
-    unread: Vec<u8>,
+    unread: VecDeque<Bytes>,
     met_eof: bool,
-    reset_by_peer: bool,
+    // Why lwIP freed the pcb on its own, see `err_function`.
+    error: Option<err_t>,
+    // Our side sent its FIN.
+    tx_shut: bool,
+    // Both sides are closed and lwIP owns the pcb now, see `release_pcb`.
+    released: bool,
+    // The window lwIP announces never exceeds this, so `unread` doesn't either.
+    recv_buffer_size: usize,
+    // Read by the application, but not yet given back to the window.
+    recv_credit: usize,
+    // Still to be read before the window starts opening again, after the
+    // buffer was made smaller.
+    recv_withheld: usize,
+    // Written by the application, waiting for room on the pcb, see `pump`.
+    queued: VecDeque<Bytes>,
+    queued_len: usize,
+    // Given to lwIP, which sends straight out of these until the client
+    // acknowledged them.
+    unacked: VecDeque<Bytes>,
+    unacked_len: usize,
+    // How much may be queued and unacknowledged at once.
+    send_buffer_size: usize,
+    // A `pump` is on its way to the lwIP thread already.
+    pump_scheduled: bool,
+    // The application asked for a FIN once `queued` is sent.
+    shutdown_requested: bool,
+    // Why lwIP refused queued data. Unlike `error`, the pcb is still ours.
+    write_error: Option<err_t>,
+    // For `TunNetif::connections`.
+    src: SocketAddr,
+    dst: SocketAddr,
+    created: Instant,
+    last_activity: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
 }
 
+unsafe impl Send for Callback {}
+
+impl Callback {
+    // Whether the pcb still belongs to the connection.
+    fn pcb_alive(&self) -> bool {
+        self.error.is_none() && !self.released
+    }
+
+    fn io_error(&self) -> Option<std::io::Error> {
+        self.error.map(|err_t| {
+            let err_kind = match_error_to_rust_error_kind(err_t);
+            std::io::Error::new(
+                err_kind.unwrap_or(std::io::ErrorKind::Other),
+                format!("tcp connection closed {}", err_t),
+            )
+        })
+    }
+
+    fn write_io_error(&self) -> Option<std::io::Error> {
+        self.io_error().or_else(|| {
+            self.write_error.map(|err_t| {
+                let err_kind = match_error_to_rust_error_kind(err_t);
+                std::io::Error::new(
+                    err_kind.unwrap_or(std::io::ErrorKind::Other),
+                    format!("tcp_write failed {}", err_t),
+                )
+            })
+        })
+    }
+
+    fn send_room(&self) -> usize {
+        self.send_buffer_size
+            .saturating_sub(self.queued_len + self.unacked_len)
+    }
+
+    // Lets go of the buffers lwIP is done with.
+    fn acked(&mut self, mut len: usize) {
+        self.bytes_out += len as u64;
+        self.last_activity = Instant::now();
+
+        while len > 0 {
+            let Some(front) = self.unacked.front_mut() else {
+                break;
+            };
+
+            let acked = len.min(front.len());
+            front.advance(acked);
+            self.unacked_len -= acked;
+            len -= acked;
+
+            if front.is_empty() {
+                self.unacked.pop_front();
+            }
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// A received pbuf chain. It is freed on the lwIP thread once every `Bytes`
+// handed out of it is gone, and its length goes back to the receive window.
 struct PBuf {
     pbuf: *mut pbuf,
+    callback: Weak<Mutex<Callback>>,
 }
 
-impl PBuf {
-    fn data(&self) -> &[u8] {
-        unsafe {
-            let ptr = (*self.pbuf).payload as *const c_void as *const u8;
-            let len = usize::from((*self.pbuf).len);
+unsafe impl Send for PBuf {}
+unsafe impl Sync for PBuf {}
 
-            std::slice::from_raw_parts(ptr, len)
-        }
+// One pbuf of a chain, as `Bytes::from_owner` wants it.
+struct Segment {
+    // Keeps the chain alive.
+    _chain: Arc<PBuf>,
+    data: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for Segment {}
+
+impl AsRef<[u8]> for Segment {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl PBuf {
+    /// The payload of every pbuf in the chain, without copying.
+    fn into_bytes(self) -> impl Iterator<Item = Bytes> {
+        let mut next = self.pbuf;
+        let chain = Arc::new(self);
+
+        std::iter::from_fn(move || unsafe {
+            let pbuf = next.as_ref()?;
+            next = pbuf.next;
+
+            Some(Bytes::from_owner(Segment {
+                _chain: chain.clone(),
+                data: pbuf.payload as *const c_void as *const u8,
+                len: usize::from(pbuf.len),
+            }))
+        })
+        .filter(|bytes| !bytes.is_empty())
     }
 }
 
 impl Drop for PBuf {
     fn drop(&mut self) {
-        unsafe { pbuf_free(self.pbuf) };
+        let pbuf_wrapper = PtrWrapper(self.pbuf);
+        let callback = self.callback.clone();
+
+        // Always queued, even on the lwIP thread, so a pbuf may be dropped
+        // with the callback locked.
+        EventLoop::get().execute(move || unsafe {
+            let pbuf_wrapper = pbuf_wrapper;
+
+            let len = usize::from((*pbuf_wrapper.0).tot_len);
+            pbuf_free(pbuf_wrapper.0);
+
+            if let Some(callback) = callback.upgrade() {
+                consumed(&callback, len);
+            }
+        });
     }
 }
 
-const SINGLE_CONNECTION_BUFFER_SIZE: usize = 1024 * 8 * 8;
+pub(crate) const SINGLE_CONNECTION_BUFFER_SIZE: usize = 1024 * 8 * 8;
 
 extern "C" fn recv_function(
     arg: *mut std::os::raw::c_void,
@@ -69,32 +491,35 @@ extern "C" fn recv_function(
     let callback = unsafe { &*callback };
 
     if p.is_null() {
-        callback.lock().unwrap().met_eof = true;
-        unsafe { tcp_recved(pcb, 0) };
-        return err_enum_t_ERR_OK as err_t;
-    }
-
+        let mut locked = callback.lock().unwrap();
+        locked.met_eof = true;
 
-    let pbuf = PBuf { pbuf: p };
-
-    let pbuf_data = pbuf.data();
-
-    {
-        let locked = &mut callback.lock().unwrap().unread;
-
-        if locked.capacity() - locked.len() < pbuf_data.len() {
-            std::mem::forget(pbuf);
-            return err_enum_t_ERR_MEM as err_t;
+        if let Some(waker) = locked.recv_waker.take() {
+            waker.wake();
         }
 
-        locked.extend_from_slice(pbuf_data);
+        if locked.tx_shut {
+            unsafe { release_pcb(&mut locked) };
+        } else {
+            unsafe { tcp_recved(pcb, 0) };
+        }
+        return err_enum_t_ERR_OK as err_t;
     }
 
-    let recv_waker = &mut callback.lock().unwrap().recv_waker;
 
-    unsafe { tcp_recved(pcb, pbuf_data.len() as u16) };
+    // No tcp_recved here: the window only opens as the application is done
+    // with the data, so the client can't send more than fits.
+    let mut locked = callback.lock().unwrap();
+    locked.bytes_in += u64::from(unsafe { (*p).tot_len });
+    locked.last_activity = Instant::now();
+
+    let pbuf = PBuf {
+        pbuf: p,
+        callback: locked.this.clone(),
+    };
+    locked.unread.extend(pbuf.into_bytes());
 
-    if let Some(waker) = recv_waker.take() {
+    if let Some(waker) = locked.recv_waker.take() {
         waker.wake();
     } else {
         // println!("Calling Recv without waker");
@@ -110,6 +535,9 @@ extern "C" fn poll_function(
     let callback = arg as *const Mutex<Callback>;
     let callback = unsafe { &*callback };
 
+    // Retries what lwIP had no memory for.
+    unsafe { pump(callback) };
+
     let write_waker = &mut callback.lock().unwrap().write_waker;
 
     if let Some(waker) = write_waker.take() {
@@ -123,286 +551,746 @@ extern "C" fn poll_function(
 
 extern "C" fn err_function(
     arg: *mut std::os::raw::c_void,
-    _: err_t
+    err: err_t
 ) {
     let callback = arg as *const Mutex<Callback>;
     let callback = unsafe { &*callback };
 
-    callback.lock().unwrap().reset_by_peer = true;
+    // The pcb is gone, so whoever waits on it has to learn about it now.
+    let mut locked = callback.lock().unwrap();
+    locked.error = Some(err);
+
+    if let Some(waker) = locked.recv_waker.take() {
+        waker.wake();
+    }
+    if let Some(waker) = locked.write_waker.take() {
+        waker.wake();
+    }
+
+    // lwIP freed the unacknowledged buffers' segments with the pcb.
+    if locked.released {
+        drop(locked);
+        unsafe { Arc::decrement_strong_count(callback) };
+    }
 }
 
 extern "C" fn sent_function(
     arg: *mut std::os::raw::c_void,
-    _: *mut tcp_pcb,
-    _: u16
+    pcb: *mut tcp_pcb,
+    len: u16
 ) -> err_t {
     // println!("Sent called");
     let callback = arg as *const Mutex<Callback>;
     let callback = unsafe { &*callback };
 
-    let write_waker = &mut callback.lock().unwrap().write_waker;
+    let mut locked = callback.lock().unwrap();
+    locked.acked(usize::from(len));
 
-    if let Some(waker) = write_waker.take() {
+    if let Some(waker) = locked.write_waker.take() {
         waker.wake();
     } else {
         // println!("Calling Sent without waker");
     }
 
+    // The last buffer a released pcb was sending from, see `release_pcb`.
+    if locked.released && locked.unacked.is_empty() {
+        drop(locked);
+        unsafe {
+            tcp_arg(pcb, std::ptr::null_mut());
+            tcp_sent(pcb, None);
+            tcp_err(pcb, None);
+            Arc::decrement_strong_count(callback);
+        }
+        return err_enum_t_ERR_OK as err_t;
+    }
+    drop(locked);
+
+    // The send buffer has room again.
+    unsafe { pump(callback) };
+
     return err_enum_t_ERR_OK as err_t;
 }
 
 impl TcpConnection {
-    pub fn new(pcb: *mut tcp_pcb, pool: std::sync::Arc<ThreadPool>) -> TcpConnection {
+    /// Takes over a pcb lwIP just handed to us, on the lwIP thread.
+    ///
+    /// `send_buffer_size` defaults to lwIP's send buffer.
+    pub(crate) fn new(
+        pcb: *mut tcp_pcb,
+        options: &TcpOptions,
+        send_buffer_size: Option<usize>,
+        connections: &Arc<Connections>,
+    ) -> TcpConnection {
+        let event_loop = EventLoop::get();
+        debug_assert!(event_loop.is_current());
+
         unsafe { assert!((*pcb).state != tcp_state_CLOSED) };
-        let callback = Callback {
+
+        let send_buffer_size = unsafe {
+            // Anything smaller couldn't even fill a segment.
+            send_buffer_size
+                .unwrap_or((*pcb).snd_buf as usize)
+                .max(usize::from((*pcb).mss))
+        };
+
+        let now = Instant::now();
+
+        let callback = Arc::new_cyclic(|this| Mutex::new(Callback {
+            pcb,
+            this: this.clone(),
             recv_waker: None,
             write_waker: None,
-            unread: Vec::with_capacity(SINGLE_CONNECTION_BUFFER_SIZE),
+            unread: VecDeque::new(),
             met_eof: false,
-            reset_by_peer: false,
-        };
-        let mut pinned = Box::pin(Mutex::new(callback));
-        let ptr = unsafe { pinned.as_mut().get_unchecked_mut() as *mut Mutex<Callback> };
+            error: None,
+            tx_shut: false,
+            released: false,
+            // What the SYN|ACK announced, see `TunNetif::set_recv_buffer_size`.
+            recv_buffer_size: unsafe { (*pcb).rcv_wnd as usize },
+            recv_credit: 0,
+            recv_withheld: 0,
+            queued: VecDeque::new(),
+            queued_len: 0,
+            unacked: VecDeque::new(),
+            unacked_len: 0,
+            send_buffer_size,
+            pump_scheduled: false,
+            shutdown_requested: false,
+            write_error: None,
+            src: unsafe { socket_addr_from_lwip(&(*pcb).remote_ip, (*pcb).remote_port) },
+            dst: unsafe { socket_addr_from_lwip(&(*pcb).local_ip, (*pcb).local_port) },
+            created: now,
+            last_activity: now,
+            bytes_in: 0,
+            bytes_out: 0,
+        }));
+        let ptr = Arc::as_ptr(&callback) as *mut Mutex<Callback>;
 
-        let recv_callback_wrapper = PtrWrapper(ptr);
-        let pcb_wrapper = PtrWrapper(pcb);
+        unsafe {
+            tcp_arg(pcb, ptr as *mut c_void);
 
-        pool.install(|| {
-            let pcb_wrapper = pcb_wrapper;
-            let recv_callback_wrapper = recv_callback_wrapper;
+            tcp_poll(pcb, Some(poll_function), 1);
+            tcp_sent(pcb, Some(sent_function));
 
-            let pcb = pcb_wrapper.0;
-            let ptr = recv_callback_wrapper.0;
+            tcp_recv(pcb, Some(recv_function));
+            tcp_err(pcb, Some(err_function));
 
-            unsafe {
-                tcp_arg(pcb, ptr as *mut c_void);
+            if options.nodelay {
+                (*pcb).flags |= TF_NODELAY as u16;
+            }
+            if let Some(idle) = options.keepalive {
+                (*pcb).so_options |= SOF_KEEPALIVE as u8;
+                (*pcb).keep_idle = u32::try_from(idle.as_millis()).unwrap_or(u32::MAX);
+            }
+            if let Some(ttl) = options.ttl {
+                (*pcb).ttl = ttl;
+            }
+        }
 
-                tcp_poll(pcb, Some(poll_function), 1);
-                tcp_sent(pcb, Some(sent_function));
+        let id = connections.insert(&callback);
 
-                tcp_recv(pcb, Some(recv_function));
-                tcp_err(pcb, Some(err_function));
+        TcpConnection {
+            event_loop,
+            callback,
+            pending: None,
+            connections: connections.clone(),
+            id,
+        }
+    }
+
+    pub(crate) fn with_pending_syn(mut self, pending: PendingSyn) -> TcpConnection {
+        self.pending = Some(pending);
+        self
+    }
+
+    /// Reads lwIP's transport metrics of the connection.
+    ///
+    /// This blocks until the lwIP thread answers, so it is meant for
+    /// diagnostics, not for every read or write. Async code should use
+    /// `info_async`.
+    pub fn info(&self) -> Result<TcpInfo> {
+        self.event_loop.run(self.info_job())
+    }
+
+    /// Like `info`, but waits without blocking the caller.
+    pub async fn info_async(&self) -> Result<TcpInfo> {
+        self.event_loop.run_async(self.info_job()).await
+    }
+
+    fn info_job(&self) -> impl FnOnce() -> Result<TcpInfo> + Send + 'static {
+        let callback = self.callback.clone();
+
+        move || unsafe {
+            let locked = callback.lock().unwrap();
+
+            if let Some(err) = locked.io_error() {
+                return Err(err);
             }
+            if locked.released {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "connection is closed",
+                ));
+            }
+
+            let pcb = &*locked.pcb;
+            // sa holds eight times the RTT, sv four times its variance.
+            Ok(TcpInfo {
+                state: pcb.state.into(),
+                rtt: slow_ticks(i32::from(pcb.sa) >> 3),
+                rtt_var: slow_ticks(i32::from(pcb.sv) >> 2),
+                rto: slow_ticks(i32::from(pcb.rto)),
+                retransmits: pcb.nrtx,
+                mss: pcb.mss,
+                cwnd: u32::from(pcb.cwnd),
+                ssthresh: u32::from(pcb.ssthresh),
+                send_window: u32::from(pcb.snd_wnd),
+                recv_window: u32::from(pcb.rcv_wnd),
+                unacked: segments_len(pcb.unacked),
+                unsent: segments_len(pcb.unsent),
+                queued: locked.queued_len,
+            })
+        }
+    }
+
+    /// Identifies the connection in `TunNetif::connections`.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// How much the client may send ahead of the application's reads.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.callback.lock().unwrap().recv_buffer_size
+    }
+
+    /// Grows or shrinks the receive buffer of this connection.
+    ///
+    /// A window that was announced can't be taken back, so a smaller buffer
+    /// takes effect as the application reads what the client sent already.
+    pub fn set_recv_buffer_size(&self, size: usize) {
+        let callback = self.callback.clone();
+
+        self.event_loop.execute(move || unsafe {
+            let (pcb, grow) = {
+                let mut locked = callback.lock().unwrap();
+                if locked.error.is_some() || locked.released {
+                    return;
+                }
+
+                // A scaled window smaller than this would be announced as 0.
+                let size = size.max(4 << (*locked.pcb).rcv_scale);
+
+                let current = locked.recv_buffer_size;
+                locked.recv_buffer_size = size;
+
+                if size < current {
+                    locked.recv_withheld += current - size;
+                    return;
+                }
+
+                let grow = size - current;
+                let cancelled = grow.min(locked.recv_withheld);
+                locked.recv_withheld -= cancelled;
+                (locked.pcb, grow - cancelled)
+            };
+
+            recved(pcb, grow);
         });
+    }
 
-        TcpConnection {
-            pcb,
-            pool,
-            pcb_freed: false,
-            callback: pinned,
+    /// Takes the next received segment without copying it.
+    ///
+    /// The segment's memory stays with lwIP, and counts against the receive
+    /// buffer, until the returned `Bytes` and all its clones are dropped.
+    /// Returns `None` once the client closed its side.
+    pub fn poll_recv_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>>> {
+        let mut locked = self.callback.lock().unwrap();
+
+        if let Some(chunk) = locked.unread.pop_front() {
+            return Poll::Ready(Ok(Some(chunk)));
+        }
+
+        if locked.met_eof {
+            return Poll::Ready(Ok(None));
+        }
+
+        if let Some(err) = locked.io_error() {
+            return Poll::Ready(Err(err));
+        }
+
+        locked.recv_waker.replace(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// See `poll_recv_chunk`.
+    pub async fn recv_chunk(&mut self) -> Result<Option<Bytes>> {
+        std::future::poll_fn(|cx| self.poll_recv_chunk(cx)).await
+    }
+
+    /// Whether the client is still waiting for the SYN-ACK, see
+    /// `TunNetif::set_deferred_handshake`.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Completes the handshake of a pending connection.
+    ///
+    /// Should the SYN-ACK fail to go out, the connection is aborted, which
+    /// reads and writes report.
+    pub fn accept(&mut self) -> Result<()> {
+        if self.pending.is_none() {
+            return Ok(());
+        }
+
+        // Timed out while waiting for us.
+        if let Some(err) = self.callback.lock().unwrap().io_error() {
+            return Err(err);
+        }
+
+        self.pending = None;
+
+        let callback = self.callback.clone();
+        self.event_loop.execute(move || unsafe {
+            let pcb = {
+                let locked = callback.lock().unwrap();
+                if !locked.pcb_alive() {
+                    return;
+                }
+                locked.pcb
+            };
+
+            let err_t = tun_tcp_accept_pending(pcb);
+            if err_t != err_enum_t_ERR_OK as err_t {
+                debug!("accept failed {}", err_t);
+                tcp_abort(pcb);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Refuses a pending connection the way `rejection` says.
+    ///
+    /// Dropping a pending connection rejects it with a RST as well.
+    pub fn reject(mut self, rejection: Rejection) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+
+        let pending_wrapper = PtrWrapper(pending);
+        let callback = self.callback.clone();
+
+        // Queued before the job of `Drop`, which then finds the pcb gone.
+        self.event_loop.execute(move || unsafe {
+            let pending_wrapper = pending_wrapper;
+            #[cfg_attr(not(feature = "icmp"), allow(unused_variables))]
+            let pending = pending_wrapper.0;
+
+            let pcb = {
+                let locked = callback.lock().unwrap();
+                if locked.error.is_some() {
+                    return;
+                }
+                locked.pcb
+            };
+
+            match rejection {
+                Rejection::Reset => tcp_abort(pcb),
+                #[cfg(feature = "icmp")]
+                Rejection::Unreachable(code) => reject_unreachable(pcb, pending, code),
+            }
+        });
+    }
+
+    /// Like `poll_write`, but lwIP sends straight out of `bytes` instead of
+    /// copying it. What was written stays referenced, and in memory, until
+    /// the client acknowledged it.
+    pub fn poll_send_bytes(&mut self, cx: &mut Context<'_>, bytes: &Bytes) -> Poll<Result<usize>> {
+        self.poll_queue(cx, bytes.len(), |len| bytes.slice(..len))
+    }
+
+    /// Writes all of `bytes` without copying it, see `poll_send_bytes`.
+    pub async fn send_bytes(&mut self, mut bytes: Bytes) -> Result<()> {
+        while !bytes.is_empty() {
+            let written = std::future::poll_fn(|cx| self.poll_send_bytes(cx, &bytes)).await?;
+            bytes.advance(written);
         }
+        Ok(())
+    }
+
+    // Queues as much of `len` bytes as the send buffer has room for, for the
+    // lwIP thread to hand to the pcb. `take` gives that many bytes.
+    fn poll_queue(
+        &mut self,
+        cx: &mut Context<'_>,
+        len: usize,
+        take: impl FnOnce(usize) -> Bytes,
+    ) -> Poll<Result<usize>> {
+        // debug!("Poll write len {}", len);
+
+        if self.pending.is_some() {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "connection is not accepted yet",
+            )));
+        }
+
+        let mut locked = self.callback.lock().unwrap();
+
+        if locked.tx_shut || locked.shutdown_requested {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "connection is shut down for writing",
+            )));
+        }
+
+        if let Some(err) = locked.write_io_error() {
+            return Poll::Ready(Err(err));
+        }
+
+        if len == 0 {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = len.min(locked.send_room());
+        if len == 0 {
+            // Woken as the client acknowledges data.
+            locked.write_waker.replace(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        locked.queued.push_back(take(len));
+        locked.queued_len += len;
+        self.schedule_pump(&mut locked);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn schedule_pump(&self, locked: &mut Callback) {
+        if locked.pump_scheduled {
+            return;
+        }
+        locked.pump_scheduled = true;
+
+        let callback = self.callback.clone();
+        self.event_loop.execute(move || unsafe { pump(&callback) });
     }
 }
 
 impl AsyncRead for TcpConnection {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        {
-            let waker = cx.waker().clone();
-            let callback = &self.as_mut().callback;
-            callback.lock().unwrap().recv_waker.replace(waker);
-        }
-
         let mut locked_callback = self.callback.lock().unwrap();
-        let locked_unread = &mut locked_callback.unread;
 
-        if locked_unread.is_empty() {
+        if locked_callback.unread.is_empty() {
             if locked_callback.met_eof {
                 return Poll::Ready(Ok(()));
             }
 
-            return Poll::Pending;
-        } else {
-
-            let mut need_call_waker_again = false;
-
-            let read_size;
-            if buf.remaining() < locked_unread.len() {
-                read_size = buf.remaining();
-                need_call_waker_again = true;
-            } else {
-                read_size = locked_unread.len();
+            if let Some(err) = locked_callback.io_error() {
+                return Poll::Ready(Err(err));
             }
 
-            {
-                let sent_data = locked_unread.drain(..read_size);
+            locked_callback.recv_waker.replace(cx.waker().clone());
+            return Poll::Pending;
+        }
 
-                buf.put_slice(sent_data.as_slice());
-            }
+        while buf.remaining() > 0 {
+            let Some(chunk) = locked_callback.unread.front_mut() else {
+                break;
+            };
 
-            if locked_callback.met_eof {
-                need_call_waker_again = true;
-            }
+            let read_size = chunk.len().min(buf.remaining());
+            buf.put_slice(&chunk[..read_size]);
+            chunk.advance(read_size);
 
-            if need_call_waker_again {
-                cx.waker().wake_by_ref();
+            if chunk.is_empty() {
+                // Frees the pbuf and opens the window on the lwIP thread.
+                locked_callback.unread.pop_front();
             }
-            return Poll::Ready(Ok(()));
         }
+
+        Poll::Ready(Ok(()))
     }
 }
 
-impl AsyncWrite for TcpConnection {
-    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
-        // debug!("Poll write len {}", buf.len());
-
-        let pcb_wrapper = PtrWrapper(self.pcb);
-        {
-            let waker = cx.waker().clone();
-            let callback = &self.as_mut().callback;
-            callback.lock().unwrap().write_waker.replace(waker);
-        }
-
-        let pool = &self.pool;
-
-        let result = pool.install(|| unsafe {
-            let pcb_wrapper = pcb_wrapper;
-            let state = (*pcb_wrapper.0).state;
-            let err = match_tcp_state_to_io_error_kind(state);
-            if let Some(err) = err {
-                // if err == std::io::ErrorKind::ConnectionAborted {
-                //     return Poll::Ready(Ok(0));
-                // }
-                let error_msg = format!("tcp state is not connected {}", state);
-                return Poll::Ready(Err(std::io::Error::new(err, error_msg)));
-            }
+impl futures::Stream for TcpConnection {
+    type Item = Result<Bytes>;
 
-            let err_t = tcp_write(
-                pcb_wrapper.0,
-                buf.as_ptr() as *const c_void,
-                buf.len() as u16,
-                TCP_WRITE_FLAG_COPY as u8,
-            );
-            // println!("tcp write result {}", err_t);
-
-            if err_t == err_enum_t_ERR_MEM as err_t {
-                // data is not writen.
-                tcp_output(pcb_wrapper.0);
-                Poll::Pending
-            } else if err_t == err_enum_t_ERR_OK as err_t{
-                Poll::Ready(Ok(buf.len()))
-            } else {
-                let err_kind = match_error_to_rust_error_kind(err_t);
-                Poll::Ready(Err(std::io::Error::new(
-                    err_kind.unwrap(),
-                    format!("tcp_write failed {}", err_t),
-                )))
-            }
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv_chunk(cx).map(Result::transpose)
+    }
+}
 
-        });
+impl AsyncWrite for TcpConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
 
-        result
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+
+        // The one copy of the data, made here rather than on the lwIP thread.
+        self.get_mut().poll_queue(cx, len, |len| {
+            let mut copy = BytesMut::with_capacity(len);
+            for buf in bufs {
+                let rest = len - copy.len();
+                copy.put_slice(&buf[..buf.len().min(rest)]);
+            }
+            copy.freeze()
+        })
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
-        let pool = &self.pool;
-        let pcb_wrapper = PtrWrapper(self.pcb);
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
 
-        let err_t = pool.install(|| {
-            let pcb_wrapper = pcb_wrapper;
+    /// Waits until lwIP took everything written so far.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let mut locked = self.callback.lock().unwrap();
 
-            unsafe { tcp_output(pcb_wrapper.0) }
-        });
+        if let Some(err) = locked.write_io_error() {
+            return Poll::Ready(Err(err));
+        }
 
-        if err_t == err_enum_t_ERR_OK as err_t {
-            Poll::Ready(Ok(()))
-        } else {
-            let err_kind = match_error_to_rust_error_kind(err_t);
-            Poll::Ready(Err(std::io::Error::new(
-                err_kind.unwrap(),
-                format!("flush failed {}", err_t),
-            )))
+        if locked.queued.is_empty() || locked.released {
+            return Poll::Ready(Ok(()));
         }
+
+        locked.write_waker.replace(cx.waker().clone());
+        self.schedule_pump(&mut locked);
+        Poll::Pending
     }
 
-    fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
-        let pcb_wrapper = PtrWrapper(self.pcb);
+    /// Sends the FIN once lwIP took everything written, and waits for that.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
         debug!("PCB shutdown");
 
-        unsafe {
-            if (*pcb_wrapper.0).state == tcp_state_CLOSED {
-                return Poll::Ready(Ok(()));
-            }
+        if self.pending.is_some() {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "connection is not accepted yet",
+            )));
         }
 
-        let pool = &self.pool;
-
-        let reset_by_peer = {
-            self.callback.lock().unwrap().reset_by_peer
-        };
+        let mut locked = self.callback.lock().unwrap();
 
-        if reset_by_peer {
+        // Shut down already, or the pcb is gone.
+        if locked.tx_shut || locked.error.is_some() || locked.released {
             return Poll::Ready(Ok(()));
         }
 
-        let state_that_free_pcb = [tcp_state_CLOSED, tcp_state_SYN_SENT, tcp_state_LISTEN];
+        if let Some(err) = locked.write_io_error() {
+            return Poll::Ready(Err(err));
+        }
 
-        let pcb_would_be_free = {
-            if state_that_free_pcb.contains(&unsafe { (*pcb_wrapper.0).state }) {
-                true
-            } else {
-                false
-            }
-        };
+        locked.shutdown_requested = true;
+        locked.write_waker.replace(cx.waker().clone());
+        self.schedule_pump(&mut locked);
+        Poll::Pending
+    }
+}
 
-        let err_t = pool.install(|| unsafe {
-            let pcb_wrapper = pcb_wrapper;
+// Forgets the connection silently, then feeds its SYN to the stack again
+// and has the accept hook answer it with the ICMP error.
+#[cfg(feature = "icmp")]
+unsafe fn reject_unreachable(pcb: *mut tcp_pcb, pending: PendingSyn, code: UnreachableCode) {
+    tcp_abandon(pcb, 0);
 
-            close_tcp_in_shutdown(pcb_wrapper.0)
-        });
+    let len = pending.syn.len() as u16;
+    let pbuf = pbuf_alloc(pbuf_layer_PBUF_RAW, len, pbuf_type_PBUF_POOL);
+    if pbuf.is_null() {
+        return;
+    }
+    pbuf_take(pbuf, pending.syn.as_ptr() as *const c_void, len);
+
+    let key = (pending.src, pending.dst);
+    pending.rejections.lock().unwrap().insert(key, code);
+    netif_input(pbuf, pending.netif);
+    pending.rejections.lock().unwrap().remove(&key);
+}
+
+// On the lwIP thread: hands queued data to the pcb, as much as it takes,
+// and sends the FIN after it if asked to. What doesn't fit waits for
+// `sent_function` or `poll_function` to make room.
+unsafe fn pump(callback: &Mutex<Callback>) {
+    let mut locked = callback.lock().unwrap();
+    locked.pump_scheduled = false;
+
+    if !locked.pcb_alive() || locked.write_error.is_some() || locked.tx_shut {
+        return;
+    }
+
+    let pcb = locked.pcb;
+    let mut written = false;
 
-        if pcb_would_be_free {
-            self.as_mut().pcb_freed = true;
+    while let Some(front_len) = locked.queued.front().map(Bytes::len) {
+        // tcp_write takes a u16 and refuses more than the send buffer holds.
+        let len = front_len
+            .min((*pcb).snd_buf as usize)
+            .min(usize::from(u16::MAX));
+        if len == 0 {
+            break;
         }
 
-        if err_t == err_enum_t_ERR_OK as err_t {
-            Poll::Ready(Ok(()))
+        // More follows right away, no need to push this one out alone.
+        let more = if len < front_len || locked.queued.len() > 1 {
+            TCP_WRITE_FLAG_MORE as u8
         } else {
-            let err_kind = match_error_to_rust_error_kind(err_t);
-            Poll::Ready(Err(std::io::Error::new(
-                err_kind.unwrap(),
-                format!("poll shutdown failed {}", err_t),
-            )))
+            0
+        };
+
+        let front = locked.queued.front_mut().unwrap();
+        let err_t = tcp_write(pcb, front.as_ptr() as *const c_void, len as u16, more);
+        // println!("tcp write result {}", err_t);
+
+        if err_t == err_enum_t_ERR_MEM as err_t {
+            // data is not writen.
+            break;
+        }
+        if err_t != err_enum_t_ERR_OK as err_t {
+            locked.write_error = Some(err_t);
+            locked.wake_writer();
+            return;
         }
+
+        let piece = front.split_to(len);
+        if front.is_empty() {
+            locked.queued.pop_front();
+        }
+        locked.queued_len -= len;
+        locked.unacked.push_back(piece);
+        locked.unacked_len += len;
+        written = true;
+    }
+
+    if locked.queued.is_empty() {
+        if locked.shutdown_requested {
+            shutdown(&mut locked);
+        }
+        // For `poll_flush` and `poll_shutdown`.
+        locked.wake_writer();
     }
-}
 
-unsafe fn close_tcp_in_shutdown(pcb: *mut tcp_pcb) -> i8 {
-    tcp_close(pcb)
+    if written {
+        // Once per batch, however many writes it had for this pcb.
+        let callback = locked.this.clone();
+        EventLoop::get().at_batch_end(move || {
+            let Some(callback) = callback.upgrade() else {
+                return;
+            };
+            let locked = callback.lock().unwrap();
+            if locked.pcb_alive() {
+                tcp_output(locked.pcb);
+            }
+        });
+    }
 }
 
-impl Drop for TcpConnection {
-    fn drop(&mut self) {
-        let reset_by_peer = {
-            self.callback.lock().unwrap().reset_by_peer
-        };
+// On the lwIP thread, with everything queued handed to lwIP: sends the FIN.
+unsafe fn shutdown(locked: &mut Callback) {
+    let err_t = tcp_shutdown(locked.pcb, 0, 1);
+    if err_t != err_enum_t_ERR_OK as err_t {
+        locked.write_error = Some(err_t);
+        return;
+    }
 
-        let closed = self.pcb_freed || reset_by_peer;
-        unsafe {
-            let pcb_wrapper = PtrWrapper(self.pcb);
+    locked.tx_shut = true;
 
-            self.pool.install(|| {
-                let pcb_wrapper = pcb_wrapper;
+    if locked.met_eof {
+        // Reading went on until the client sent its FIN as well.
+        release_pcb(locked);
+    }
+}
 
-                if !closed {
-                    tcp_abort(pcb_wrapper.0);
-                }
-            });
+// On the lwIP thread: gives `len` bytes the application is done with back
+// to the window, once enough came together to be worth a window update.
+unsafe fn consumed(callback: &Mutex<Callback>, len: usize) {
+    let (pcb, credit) = {
+        let mut locked = callback.lock().unwrap();
+
+        let withheld = len.min(locked.recv_withheld);
+        locked.recv_withheld -= withheld;
+        locked.recv_credit += len - withheld;
+
+        let worth_it = locked.recv_credit > 0
+            && (locked.unread.is_empty() || locked.recv_credit >= locked.recv_buffer_size / 4);
+        if !worth_it || !locked.pcb_alive() {
+            return;
         }
+
+        (locked.pcb, std::mem::take(&mut locked.recv_credit))
+    };
+
+    recved(pcb, credit);
+}
+
+unsafe fn recved(pcb: *mut tcp_pcb, mut len: usize) {
+    while len > 0 {
+        let chunk = len.min(usize::from(u16::MAX));
+        tcp_recved(pcb, chunk as u16);
+        len -= chunk;
     }
 }
 
-fn match_tcp_state_to_io_error_kind(state: tcp_state) -> Option<std::io::ErrorKind> {
-    match state {
-        tcp_state_CLOSED => Some(std::io::ErrorKind::ConnectionAborted),
-        tcp_state_CLOSE_WAIT => Some(std::io::ErrorKind::ConnectionAborted),
-        tcp_state_CLOSING => Some(std::io::ErrorKind::ConnectionAborted),
-        tcp_state_FIN_WAIT_1 => Some(std::io::ErrorKind::ConnectionAborted),
-        tcp_state_FIN_WAIT_2 => Some(std::io::ErrorKind::ConnectionAborted),
-        _ => None
+/// Leaves a pcb whose both directions are closed to lwIP, which finishes
+/// the close and frees it on its own. It must not call back into a
+/// `TcpConnection` that may be gone by then, except to hand back buffers it
+/// still sends from: those keep the callback alive until they are acked.
+unsafe fn release_pcb(locked: &mut Callback) {
+    let pcb = locked.pcb;
+    locked.released = true;
+
+    tcp_recv(pcb, None);
+    tcp_poll(pcb, None, 0);
+
+    if !locked.unacked.is_empty() {
+        // Given up in `sent_function` or `err_function`.
+        let this = locked.this.upgrade().expect("callback of a live connection");
+        tcp_arg(pcb, Arc::into_raw(this) as *mut c_void);
+    } else {
+        tcp_arg(pcb, std::ptr::null_mut());
+        tcp_sent(pcb, None);
+        tcp_err(pcb, None);
+    }
+
+    tcp_shutdown(pcb, 1, 0);
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        self.connections.remove(self.id);
+
+        let callback = self.callback.clone();
+
+        // Keeps the callback alive for lwIP until the pcb is let go of.
+        self.event_loop.execute(move || unsafe {
+            let mut locked = callback.lock().unwrap();
+
+            if locked.pcb_alive() {
+                if locked.tx_shut {
+                    // Our FIN is on its way, let it be and only refuse
+                    // whatever the client still sends.
+                    release_pcb(&mut locked);
+                } else {
+                    let pcb = locked.pcb;
+                    drop(locked);
+                    // Calls `err_function`, which takes the lock.
+                    tcp_abort(pcb);
+                    locked = callback.lock().unwrap();
+                }
+            }
+
+            locked.unread.clear();
+            locked.queued.clear();
+        });
     }
 }
 
@@ -414,8 +1302,9 @@ fn match_error_to_rust_error_kind(err: err_t) -> Option<std::io::ErrorKind> {
         err_enum_t_ERR_USE => Some(std::io::ErrorKind::AddrInUse),
         err_enum_t_ERR_ABRT => Some(std::io::ErrorKind::ConnectionAborted),
         err_enum_t_ERR_RST => Some(std::io::ErrorKind::ConnectionReset),
-        err_enum_t_Err_CLSD => Some(std::io::ErrorKind::ConnectionAborted),
+        err_enum_t_ERR_CLSD => Some(std::io::ErrorKind::ConnectionAborted),
         err_enum_t_ERR_CONN => Some(std::io::ErrorKind::NotConnected),
+        err_enum_t_ERR_TIMEOUT => Some(std::io::ErrorKind::TimedOut),
         _ => {
             Some(std::io::ErrorKind::Other)
         }
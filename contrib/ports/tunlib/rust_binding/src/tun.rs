@@ -1,10 +1,53 @@
 use crate::lwip_binding::{
-    self, err_t, netif, netif_input, pbuf, pbuf_alloc, pbuf_layer_PBUF_RAW, pbuf_take,
-    pbuf_type_PBUF_POOL, tcp_pcb, tcp_tcp_get_tcp_addrinfo, tun_device_callback, tun_netif_new,
+    err_t, ip_addr_t, netif, netif_input, pbuf,
+    pbuf_alloc, pbuf_layer_PBUF_RAW, pbuf_take, pbuf_type_PBUF_POOL, tcp_abort, tcp_pcb,
+    tcp_tcp_get_tcp_addrinfo, tun_device_callback, tun_netif_new,
+    tun_syn_decision_t, tun_syn_decision_t_TUN_SYN_ACCEPT,
+    tun_syn_decision_t_TUN_SYN_ACCEPT_DEFERRED, tun_syn_decision_t_TUN_SYN_DROP,
+    tun_syn_decision_t_TUN_SYN_RESET, tun_syn_info_t, TUN_SYN_NO_WSCALE,
+    tun_netif_set_mtu, tun_netif_remove, tun_netif_tcp_count,
 };
-use rayon::ThreadPool;
-use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(feature = "icmp")]
+use crate::lwip_binding::{
+    tun_syn_decision_t_TUN_SYN_HOST_UNREACHABLE, tun_syn_decision_t_TUN_SYN_PORT_UNREACHABLE,
+};
+#[cfg(feature = "ipv6")]
+use crate::lwip_binding::{
+    err_enum_t_ERR_OK, lwip_ip_addr_type_IPADDR_TYPE_V6, tun_netif_add_ip6_address,
+};
+#[cfg(feature = "udp")]
+use crate::lwip_binding::udp_pcb;
+use crate::device::PacketDevice;
+#[cfg(target_os = "linux")]
+use crate::device::TunFd;
+use crate::event_loop::EventLoop;
+use crate::packet::Packet;
+use crate::tcp::{
+    ConnectionId, ConnectionSnapshot, Connections, PendingSyn, TcpConnection, TcpOptions,
+};
+use bytes::Bytes;
+use core::task::{Context, Poll};
+use futures::task::AtomicWaker;
+use futures::{SinkExt, StreamExt};
+use log::debug;
+use log::warn;
+#[cfg(feature = "icmp")]
+use std::cell::Cell;
+#[cfg(feature = "icmp")]
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::future::Future;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::os::fd::OwnedFd;
 use std::os::raw::c_void;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
 pub struct TunNetif {
     netif: *mut netif,
@@ -12,57 +55,432 @@ pub struct TunNetif {
     pub output_fn_set: bool
 }
 
+// Everything behind the pointers is only touched on the lwIP thread.
+unsafe impl Send for TunNetif {}
+unsafe impl Sync for TunNetif {}
+
 struct NetIfContext {
     pipe: Box<dyn Pipe>,
-    pool: std::sync::Arc<ThreadPool>,
-    output: Option<Box<dyn Fn(&[u8]) -> ()>>,
+    output: Option<Box<dyn Fn(Packet)>>,
+    #[cfg(feature = "udp")]
+    new_udp_flows: std::sync::Mutex<Vec<crate::udp::UdpFlow>>,
+    #[cfg(feature = "udp")]
+    flows: crate::udp::Flows,
+    incoming: Option<mpsc::Sender<(TcpConnection, ConnectionInfo)>>,
+    deferred_handshake: bool,
+    #[cfg(feature = "icmp")]
+    rejections: Rejections,
+    // The packet `netif_input` is working on, so a pending connection can
+    // keep its SYN around.
+    #[cfg(feature = "icmp")]
+    current_packet: Cell<*const [u8]>,
+    #[cfg(feature = "icmp")]
+    netif: Cell<*mut netif>,
+    timer_policy: TimerPolicy,
+    send_buffer_size: Option<usize>,
+    tcp_options: TcpOptions,
+    max_connections: Option<usize>,
+    connections: Arc<Connections>,
+    // See `TunNetif::shutdown`.
+    shutting_down: bool,
+}
+
+// The packets `TunNetif::run` queues in either direction.
+const DEVICE_QUEUE_LEN: usize = 256;
+
+// How often `TunNetif::shutdown` checks whether the connections are closed.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When the lwIP thread runs the stack's timers.
+///
+/// All netifs share the thread, so the most demanding policy of the netifs
+/// alive wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerPolicy {
+    /// Sleep until the next timer is due. Timers fire on time, and an idle
+    /// stack doesn't wake up at all.
+    Deadline,
+    /// Check the timers at most once per interval, trading timer precision
+    /// for fewer wakeups.
+    Interval(Duration),
+}
+
+/// SYNs of rejected pending connections that are being fed to the stack
+/// again, to be answered with the given ICMP error.
+#[cfg(feature = "icmp")]
+pub(crate) type Rejections =
+    std::sync::Arc<std::sync::Mutex<HashMap<(SocketAddr, SocketAddr), UnreachableCode>>>;
+
+/// An intercepted TCP connection, as told by its addresses and its SYN.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    /// The tun client's address.
+    pub src: SocketAddr,
+    /// The address the client connected to.
+    pub dst: SocketAddr,
+    /// The `TunNetif::index` of the netif the SYN arrived on.
+    pub netif_index: u8,
+    /// TTL, or hop limit for IPv6, of the SYN.
+    pub ttl: u8,
+    /// TOS byte, or traffic class for IPv6, of the SYN.
+    pub tos: u8,
+    /// The MSS option of the SYN.
+    pub mss: Option<u16>,
+    /// The window scale option of the SYN.
+    pub window_scale: Option<u8>,
+    /// Whether the SYN carried the SACK-permitted option.
+    pub sack_permitted: bool,
+}
+
+impl ConnectionInfo {
+    /// The DSCP bits of `tos`.
+    pub fn dscp(&self) -> u8 {
+        self.tos >> 2
+    }
+}
+
+/// Connections accepted by a `TunNetif`, see `TunNetif::incoming`.
+pub struct Incoming {
+    receiver: mpsc::Receiver<(TcpConnection, ConnectionInfo)>,
+}
+
+impl futures::Stream for Incoming {
+    type Item = (TcpConnection, ConnectionInfo);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// The packets a `TunNetif` sends, see `TunNetif::split`.
+pub struct Outbound {
+    receiver: mpsc::Receiver<Bytes>,
+    _netif: Arc<TunNetif>,
+}
+
+impl futures::Stream for Outbound {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Takes the packets a `TunNetif` receives, see `TunNetif::split`.
+pub struct Inbound {
+    netif: Arc<TunNetif>,
+    capacity: usize,
+    in_flight: Arc<InFlight>,
+}
+
+// The packets queued on the lwIP thread by an `Inbound`.
+struct InFlight {
+    count: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+// Gives back its slot once the stack is done with it.
+struct InboundPacket {
+    data: Bytes,
+    in_flight: Arc<InFlight>,
+}
+
+impl AsRef<[u8]> for InboundPacket {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for InboundPacket {
+    fn drop(&mut self) {
+        self.in_flight.count.fetch_sub(1, Ordering::AcqRel);
+        self.in_flight.waker.wake();
+    }
+}
+
+impl Inbound {
+    // Ready once at most `limit` packets are in flight.
+    fn poll_in_flight(&self, cx: &mut Context<'_>, limit: usize) -> Poll<()> {
+        if self.in_flight.count.load(Ordering::Acquire) <= limit {
+            return Poll::Ready(());
+        }
+        self.in_flight.waker.register(cx.waker());
+        // A packet may have been processed before the waker was in place.
+        if self.in_flight.count.load(Ordering::Acquire) <= limit {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl futures::Sink<Bytes> for Inbound {
+    type Error = std::io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_in_flight(cx, self.capacity - 1).map(Ok)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        self.in_flight.count.fetch_add(1, Ordering::AcqRel);
+        self.netif.queue_input(InboundPacket { data: item, in_flight: self.in_flight.clone() });
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_in_flight(cx, 0).map(Ok)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Resolves with the error that stopped the device of `TunNetif::from_fd`.
+///
+/// Dropping it stops the device and frees the netif.
+#[cfg(target_os = "linux")]
+pub struct DeviceStatus {
+    task: tokio::task::JoinHandle<io::Result<()>>,
+    netif: Arc<TunNetif>,
+}
+
+#[cfg(target_os = "linux")]
+impl DeviceStatus {
+    /// The netif the device carries the packets of.
+    pub fn netif(&self) -> &TunNetif {
+        &self.netif
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Future for DeviceStatus {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx).map(|result| match result {
+            Ok(result) => result,
+            Err(e) => Err(io::Error::other(e)),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DeviceStatus {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What to do with a SYN, see `Pipe::decide_new_connection`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SynDecision {
+    /// Complete the handshake and hand out the connection.
+    Accept,
+    /// Answer with a RST, like a closed port.
+    Reset,
+    /// Answer with an ICMP destination unreachable.
+    #[cfg(feature = "icmp")]
+    Unreachable(UnreachableCode),
+    /// Ignore the SYN, the client will retransmit it.
+    Drop,
+}
+
+#[cfg(feature = "icmp")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreachableCode {
+    Host,
+    Port,
 }
 
 pub trait Pipe {
-    fn handle_new_connection(&self, conn: crate::tcp::TcpConnection, dst: SocketAddr);
+    /// Called for every SYN before the stack allocates anything for it.
+    fn decide_new_connection(&self, _src: SocketAddr, _dst: SocketAddr) -> SynDecision {
+        SynDecision::Accept
+    }
+
+    fn handle_new_connection(&self, conn: crate::tcp::TcpConnection, info: ConnectionInfo);
+
+    /// Called for the first datagram of a UDP flow that has no `UdpFlow` yet.
+    /// Dropping the flow discards the datagram.
+    #[cfg(feature = "udp")]
+    fn handle_new_udp_flow(&self, _flow: crate::udp::UdpFlow, _dst: SocketAddr) {}
+}
+
+#[cfg(feature = "ipv6")]
+pub(crate) fn socket_addr_from_lwip(ip: &ip_addr_t, port: u16) -> SocketAddr {
+    if u32::from(ip.type_) == lwip_ip_addr_type_IPADDR_TYPE_V6 {
+        let words = unsafe { ip.u_addr.ip6.addr };
+
+        let mut octets = [0u8; 16];
+        for (chunk, word) in octets.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_ne_bytes());
+        }
+        SocketAddr::new(Ipv6Addr::from(octets).into(), port)
+    } else {
+        let addr: [u8; 4] = unsafe { ip.u_addr.ip4.addr }.to_ne_bytes();
+
+        SocketAddr::new(Ipv4Addr::from(addr).into(), port)
+    }
+}
+
+// Without IPv6, lwIP's addresses are plain IPv4 ones.
+#[cfg(not(feature = "ipv6"))]
+pub(crate) fn socket_addr_from_lwip(ip: &ip_addr_t, port: u16) -> SocketAddr {
+    let addr: [u8; 4] = ip.addr.to_ne_bytes();
+
+    SocketAddr::new(Ipv4Addr::from(addr).into(), port)
+}
+
+extern "C" fn accept_new_connection_callback(
+    arg: *mut ::std::os::raw::c_void,
+    src_ip: *const ip_addr_t,
+    src_port: u16,
+    dst_ip: *const ip_addr_t,
+    dst_port: u16,
+) -> tun_syn_decision_t {
+    let context = unsafe { (arg as *const NetIfContext).as_ref().unwrap() };
+
+    if context.shutting_down {
+        return tun_syn_decision_t_TUN_SYN_RESET;
+    }
+
+    let src = socket_addr_from_lwip(unsafe { &*src_ip }, src_port);
+    let dst = socket_addr_from_lwip(unsafe { &*dst_ip }, dst_port);
+
+    #[cfg(feature = "icmp")]
+    match context.rejections.lock().unwrap().remove(&(src, dst)) {
+        Some(UnreachableCode::Host) => return tun_syn_decision_t_TUN_SYN_HOST_UNREACHABLE,
+        Some(UnreachableCode::Port) => return tun_syn_decision_t_TUN_SYN_PORT_UNREACHABLE,
+        None => {}
+    }
+
+    match context.pipe.decide_new_connection(src, dst) {
+        SynDecision::Accept => {}
+        SynDecision::Reset => return tun_syn_decision_t_TUN_SYN_RESET,
+        #[cfg(feature = "icmp")]
+        SynDecision::Unreachable(UnreachableCode::Host) => {
+            return tun_syn_decision_t_TUN_SYN_HOST_UNREACHABLE
+        }
+        #[cfg(feature = "icmp")]
+        SynDecision::Unreachable(UnreachableCode::Port) => {
+            return tun_syn_decision_t_TUN_SYN_PORT_UNREACHABLE
+        }
+        SynDecision::Drop => return tun_syn_decision_t_TUN_SYN_DROP,
+    }
+
+    if let Some(max_connections) = context.max_connections {
+        if context.connections.len() >= max_connections {
+            return tun_syn_decision_t_TUN_SYN_RESET;
+        }
+    }
+
+    match &context.incoming {
+        // No room for another connection, so don't even start the handshake.
+        Some(incoming) if incoming.capacity() == 0 => tun_syn_decision_t_TUN_SYN_RESET,
+        _ if context.deferred_handshake => tun_syn_decision_t_TUN_SYN_ACCEPT_DEFERRED,
+        _ => tun_syn_decision_t_TUN_SYN_ACCEPT,
+    }
+}
+
+fn tcp_addr(pcb: *mut tcp_pcb, local: bool) -> Result<SocketAddr, err_t> {
+    let mut ip: ip_addr_t = unsafe { std::mem::zeroed() };
+    let mut port: u16 = 0;
+    let err = unsafe { tcp_tcp_get_tcp_addrinfo(pcb, local as i32, &mut ip, &mut port) };
+
+    if err != crate::lwip_binding::err_enum_t_ERR_OK as err_t {
+        return Err(err);
+    }
+
+    Ok(socket_addr_from_lwip(&ip, port))
 }
 
 extern "C" fn new_connection_callback(
     arg: *mut ::std::os::raw::c_void,
     newpcb: *mut tcp_pcb,
+    syn: *const tun_syn_info_t,
     err: err_t,
 ) -> err_t {
     println!("New Connection from lwip {:?}", newpcb);
-    if err != crate::lwip_binding::err_enum_t_ERR_OK as err_t {
+    let pending = err == crate::lwip_binding::err_enum_t_ERR_INPROGRESS as err_t;
+    if err != crate::lwip_binding::err_enum_t_ERR_OK as err_t && !pending {
         return err;
     }
     let context = unsafe { (arg as *const NetIfContext).as_ref().unwrap() };
 
-    let pool = &context.pool;
+    let syn = unsafe { &*syn };
 
-    let pcb_wrapper = PtrWrapper(newpcb);
+    let (src, dst) = match (tcp_addr(newpcb, false), tcp_addr(newpcb, true)) {
+        (Ok(src), Ok(dst)) => (src, dst),
+        (Err(err), _) | (_, Err(err)) => return err,
+    };
 
-    let socket_addr = pool.install(|| {
-        let pcb_wrapper = pcb_wrapper;
+    let info = ConnectionInfo {
+        src,
+        dst,
+        netif_index: syn.netif_idx,
+        ttl: syn.ttl,
+        tos: syn.tos,
+        mss: Some(syn.mss).filter(|mss| *mss != 0),
+        window_scale: Some(syn.wscale).filter(|wscale| u32::from(*wscale) != TUN_SYN_NO_WSCALE),
+        sack_permitted: syn.sack_permitted != 0,
+    };
 
-        let mut remote_ip: crate::lwip_binding::ip4_addr_t = unsafe { std::mem::zeroed() };
-        let mut remote_port: u16 = 0;
-        let err = unsafe { tcp_tcp_get_tcp_addrinfo(pcb_wrapper.0, 1, &mut remote_ip, &mut remote_port) };
+    let mut conn = TcpConnection::new(
+        newpcb,
+        &context.tcp_options,
+        context.send_buffer_size,
+        &context.connections,
+    );
 
-        if err != crate::lwip_binding::err_enum_t_ERR_OK as err_t {
-            return Result::Err(err);
-        }
+    if pending {
+        conn = conn.with_pending_syn(pending_syn(context, &info));
+    }
 
-        let addr: [u8; 4] = unsafe { std::mem::transmute(remote_ip) };
+    if let Some(incoming) = &context.incoming {
+        if let Err(full) = incoming.try_send((conn, info)) {
+            // The queue filled up during the handshake. lwIP only takes
+            // ERR_ABRT for a pcb that is gone, and the connection finds it
+            // gone when it is dropped.
+            unsafe { tcp_abort(newpcb) };
+            drop(full);
+            return crate::lwip_binding::err_enum_t_ERR_ABRT as err_t;
+        }
+        return crate::lwip_binding::err_enum_t_ERR_OK as err_t;
+    }
 
-        let ip_addr = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
-        let socket_addr = SocketAddr::new(ip_addr.into(), remote_port);
+    context.pipe.handle_new_connection(conn, info);
+    return crate::lwip_binding::err_enum_t_ERR_OK as err_t;
+}
 
-        Ok(socket_addr)
-    });
+// What `TcpConnection::reject` needs of a connection the SYN of the packet
+// `input_data` is working on just created.
+#[cfg(feature = "icmp")]
+fn pending_syn(context: &NetIfContext, info: &ConnectionInfo) -> PendingSyn {
+    let syn = unsafe { &*context.current_packet.get() };
 
-    if let Err(err) = socket_addr {
-        return err;
+    PendingSyn {
+        syn: syn.to_vec(),
+        netif: context.netif.get(),
+        src: info.src,
+        dst: info.dst,
+        rejections: context.rejections.clone(),
     }
+}
+
+#[cfg(not(feature = "icmp"))]
+fn pending_syn(_context: &NetIfContext, _info: &ConnectionInfo) -> PendingSyn {
+    PendingSyn
+}
+
+#[cfg(feature = "udp")]
+extern "C" fn new_udp_flow_callback(arg: *mut ::std::os::raw::c_void, pcb: *mut udp_pcb) -> err_t {
+    let context = unsafe { (arg as *const NetIfContext).as_ref().unwrap() };
 
-    let conn = crate::tcp::TcpConnection::new(newpcb, pool.clone());
+    // lwIP delivers the datagram to this pcb right after we return, so the
+    // pipe only gets the flow once `netif_input` is done with it.
+    let flow = crate::udp::UdpFlow::new(pcb, &context.flows);
+    context.new_udp_flows.lock().unwrap().push(flow);
 
-    context.pipe.handle_new_connection(conn, socket_addr.unwrap());
     return crate::lwip_binding::err_enum_t_ERR_OK as err_t;
 }
 
@@ -70,14 +488,11 @@ extern "C" fn output_data(
     arg: *mut ::std::os::raw::c_void,
     _: *mut netif,
     pbuf: *mut pbuf,
-    _: *const crate::lwip_binding::ip4_addr_t,
 ) -> err_t {
     unsafe {
         let context = (arg as *const NetIfContext).as_ref().unwrap();
         if let Some(output_fn) = context.output.as_ref() {
-            let data =
-                std::slice::from_raw_parts((*pbuf).payload as *const u8, (*pbuf).len as usize);
-            output_fn(data);
+            output_fn(Packet::new(pbuf));
         }
     }
 
@@ -89,47 +504,135 @@ pub(crate) struct PtrWrapper<T>(pub(crate) T);
 unsafe impl<T> Send for PtrWrapper<T> {}
 unsafe impl<T> Sync for PtrWrapper<T> {}
 
-impl TunNetif {
-    pub fn new(
-        handle: tokio::runtime::Handle,
-        ip_addr: Ipv4Addr,
-        net_mask: Ipv4Addr,
-        gateway: Ipv4Addr,
-        pipe: Box<dyn Pipe>,
-    ) -> TunNetif {
-        unsafe {
-            let ip_addr: u32 = std::mem::transmute(ip_addr.octets());
-            let net_mask: u32 = std::mem::transmute(net_mask.octets());
-            let gateway: u32 = std::mem::transmute(gateway.octets());
+/// Sets up a `TunNetif`, see `TunNetif::builder`.
+pub struct TunNetifBuilder {
+    ip_addr: Ipv4Addr,
+    net_mask: Ipv4Addr,
+    gateway: Ipv4Addr,
+    #[cfg(feature = "ipv6")]
+    ip6_addrs: Vec<Ipv6Addr>,
+    mtu: u16,
+    recv_buffer_size: usize,
+    send_buffer_size: Option<usize>,
+    timer_policy: TimerPolicy,
+    thread_name: Option<String>,
+    max_connections: Option<usize>,
+    tcp_options: TcpOptions,
+}
+
+impl TunNetifBuilder {
+    /// The IPv4 identity of the netif, `0.0.0.0` for all three by default.
+    pub fn ipv4(mut self, ip_addr: Ipv4Addr, net_mask: Ipv4Addr, gateway: Ipv4Addr) -> Self {
+        self.ip_addr = ip_addr;
+        self.net_mask = net_mask;
+        self.gateway = gateway;
+        self
+    }
+
+    /// Like the IPv4 address, these only give the netif an identity:
+    /// connections to every destination are intercepted either way.
+    #[cfg(feature = "ipv6")]
+    pub fn ipv6(mut self, ip6_addrs: &[Ipv6Addr]) -> Self {
+        self.ip6_addrs = ip6_addrs.to_vec();
+        self
+    }
+
+    /// The MTU of the tun device, 1500 by default.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// The receive buffer of new connections, see
+    /// `TunNetif::set_recv_buffer_size`.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// How much a connection may have written but not yet acknowledged by
+    /// the client. Defaults to the stack's `TCP_SND_BUF`.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// `TimerPolicy::Deadline` by default.
+    pub fn timer_policy(mut self, policy: TimerPolicy) -> Self {
+        self.timer_policy = policy;
+        self
+    }
+
+    /// Names the lwIP thread, `lwip` by default. The thread is shared by
+    /// all netifs, so only the first netif built gets to name it.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.thread_name = Some(name.into());
+        self
+    }
 
-            lwip_binding::lwip_init();
+    /// Answers SYNs with a RST while this many connections of the netif are
+    /// alive. Unlimited by default.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// The options new connections start with.
+    pub fn tcp_options(mut self, options: TcpOptions) -> Self {
+        self.tcp_options = options;
+        self
+    }
 
-            let pool = rayon::ThreadPoolBuilder::new()
-                .num_threads(1)
-                .thread_name(|i| format!("lwip-TCP-{}", i))
-                .build()
-                .unwrap();
+    /// lwIP's timers run on the lwIP thread for as long as a netif exists.
+    pub fn build(self, pipe: Box<dyn Pipe>) -> TunNetif {
+        unsafe {
+            let ip_addr: u32 = std::mem::transmute(self.ip_addr.octets());
+            let net_mask: u32 = std::mem::transmute(self.net_mask.octets());
+            let gateway: u32 = std::mem::transmute(self.gateway.octets());
 
-            let arc_pool = std::sync::Arc::new(pool);
+            let event_loop = EventLoop::start(self.thread_name.as_deref());
 
             let context = NetIfContext {
                 pipe,
                 output: None,
-                pool: arc_pool.clone(),
+                #[cfg(feature = "udp")]
+                new_udp_flows: std::sync::Mutex::new(Vec::new()),
+                #[cfg(feature = "udp")]
+                flows: Default::default(),
+                incoming: None,
+                deferred_handshake: false,
+                #[cfg(feature = "icmp")]
+                rejections: Default::default(),
+                #[cfg(feature = "icmp")]
+                current_packet: Cell::new(&[]),
+                #[cfg(feature = "icmp")]
+                netif: Cell::new(std::ptr::null_mut()),
+                timer_policy: self.timer_policy,
+                send_buffer_size: self.send_buffer_size,
+                tcp_options: self.tcp_options,
+                max_connections: self.max_connections,
+                connections: Default::default(),
+                shutting_down: false,
             };
 
             let boxed = Box::new(context);
             let addr = Box::into_raw(boxed);
             let context_wrapper = PtrWrapper(addr as *const NetIfContext);
 
-            let ptr_to_netif = arc_pool.install(|| {
+            let ptr_to_netif = event_loop.run(|| {
                 let wrapper = context_wrapper;
                 let context_ptr = wrapper.0;
 
                 let callback: crate::lwip_binding::tun_device_callback = tun_device_callback {
+                    accept_new_connection: Some(accept_new_connection_callback),
                     new_connection: Some(new_connection_callback),
+                    #[cfg(feature = "udp")]
+                    new_udp_flow: Some(new_udp_flow_callback),
+                    #[cfg(not(feature = "udp"))]
+                    new_udp_flow: None,
                     output: Some(output_data),
                     arg: context_ptr as *mut c_void,
+                    recv_window: u32::try_from(self.recv_buffer_size).unwrap_or(u32::MAX),
                 };
 
                 let boxed_callback = Box::new(callback);
@@ -141,19 +644,26 @@ impl TunNetif {
                     gateway.to_be(),
                     addr_boxed_callback,
                 );
-                PtrWrapper(ptr)
-            });
+                tun_netif_set_mtu(ptr, self.mtu);
 
-            let cloned_pool = arc_pool.clone();
-
-            handle.spawn(async move {
-                let mut interval = tokio::time::interval(std::time::Duration::from_millis(500));
-                loop {
-                    interval.tick().await;
-                    cloned_pool.install(|| {
-                        crate::lwip_binding::sys_check_timeouts();
-                    })
+                #[cfg(feature = "ipv6")]
+                for ip6_addr in &self.ip6_addrs {
+                    let octets = ip6_addr.octets();
+                    let mut words = [0u32; 4];
+                    for (word, chunk) in words.iter_mut().zip(octets.chunks_exact(4)) {
+                        *word = u32::from_ne_bytes(chunk.try_into().unwrap());
+                    }
+                    let err_t = tun_netif_add_ip6_address(ptr, words.as_ptr());
+                    if err_t != err_enum_t_ERR_OK as err_t {
+                        warn!("Couldn't add IPv6 address {} to the netif: {}", ip6_addr, err_t);
+                    }
                 }
+
+                #[cfg(feature = "icmp")]
+                (*context_ptr).netif.set(ptr);
+                event_loop.hold_timers(self.timer_policy);
+
+                PtrWrapper(ptr)
             });
 
             TunNetif {
@@ -163,35 +673,366 @@ impl TunNetif {
             }
         }
     }
+}
 
-    pub fn input_data(&self, data: &[u8]) {
-        unsafe {
-            let netif_wrapper = PtrWrapper(self.netif);
-            (*self.context).pool.install(|| {
-                let netif_wrapper = netif_wrapper;
-                let netif = netif_wrapper.0;
+impl TunNetif {
+    pub fn builder() -> TunNetifBuilder {
+        TunNetifBuilder {
+            ip_addr: Ipv4Addr::UNSPECIFIED,
+            net_mask: Ipv4Addr::UNSPECIFIED,
+            gateway: Ipv4Addr::UNSPECIFIED,
+            #[cfg(feature = "ipv6")]
+            ip6_addrs: Vec::new(),
+            mtu: 1500,
+            recv_buffer_size: crate::tcp::SINGLE_CONNECTION_BUFFER_SIZE,
+            send_buffer_size: None,
+            timer_policy: TimerPolicy::Deadline,
+            thread_name: None,
+            max_connections: None,
+            tcp_options: TcpOptions::default(),
+        }
+    }
 
-                let pbuf = pbuf_alloc(pbuf_layer_PBUF_RAW, data.len() as u16, pbuf_type_PBUF_POOL);
-                pbuf_take(pbuf, data.as_ptr() as *const c_void, data.len() as u16);
+    /// Like the IPv4 address, `ip6_addrs` only give the netif an identity:
+    /// connections to every destination are intercepted either way. They
+    /// are ignored without the `ipv6` feature.
+    ///
+    /// Shorthand for `TunNetif::builder` with the defaults.
+    pub fn new(
+        ip_addr: Ipv4Addr,
+        net_mask: Ipv4Addr,
+        gateway: Ipv4Addr,
+        ip6_addrs: &[Ipv6Addr],
+        pipe: Box<dyn Pipe>,
+    ) -> TunNetif {
+        let builder = TunNetif::builder().ipv4(ip_addr, net_mask, gateway);
 
-                netif_input(pbuf, netif);
-            });
+        #[cfg(feature = "ipv6")]
+        let builder = builder.ipv6(ip6_addrs);
+        #[cfg(not(feature = "ipv6"))]
+        if !ip6_addrs.is_empty() {
+            warn!("IPv6 addresses given without the ipv6 feature");
         }
+
+        builder.build(pipe)
+    }
+
+    /// Queues a packet read from the tun device for the stack, without
+    /// waiting for it to be processed.
+    pub fn input_data(&self, data: &[u8]) {
+        self.queue_input(data.to_vec());
+    }
+
+    // The packet is dropped on the lwIP thread once the stack is done with
+    // it, which is what `Inbound` counts on.
+    fn queue_input<P: AsRef<[u8]> + Send + 'static>(&self, packet: P) {
+        let netif_wrapper = PtrWrapper(self.netif);
+        let context_wrapper = PtrWrapper(self.context);
+
+        EventLoop::get().execute(move || unsafe {
+            let netif_wrapper = netif_wrapper;
+            let context_wrapper = context_wrapper;
+            let netif = netif_wrapper.0;
+            #[cfg_attr(not(any(feature = "icmp", feature = "udp")), allow(unused_variables))]
+            let context = &*context_wrapper.0;
+
+            let packet = packet.as_ref();
+
+            let pbuf = pbuf_alloc(pbuf_layer_PBUF_RAW, packet.len() as u16, pbuf_type_PBUF_POOL);
+            if pbuf.is_null() {
+                return;
+            }
+            pbuf_take(pbuf, packet.as_ptr() as *const c_void, packet.len() as u16);
+
+            #[cfg(feature = "icmp")]
+            context.current_packet.set(packet);
+            netif_input(pbuf, netif);
+            #[cfg(feature = "icmp")]
+            context.current_packet.set(&[]);
+
+            #[cfg(feature = "udp")]
+            for flow in std::mem::take(&mut *context.new_udp_flows.lock().unwrap()) {
+                let dst = flow.local_addr();
+                context.pipe.handle_new_udp_flow(flow, dst);
+            }
+        });
+    }
+
+    /// Identifies this netif in `ConnectionInfo::netif_index`.
+    pub fn index(&self) -> u8 {
+        unsafe { (*self.netif).num + 1 }
     }
 
+    /// Called with every packet the stack sends, in one piece.
     pub fn set_output_fn(&mut self, output: Box<dyn Fn(&[u8]) -> ()>) {
+        self.set_packet_output_fn(Box::new(move |packet: Packet| output(&packet.contiguous())));
+    }
+
+    /// Like `set_output_fn`, but hands over the packets as lwIP built them,
+    /// so they can be written later without copying.
+    pub fn set_packet_output_fn(&mut self, output: Box<dyn Fn(Packet)>) {
         self.output_fn_set = true;
-        unsafe {
-            (self.context as *mut NetIfContext).as_mut().unwrap().output = Some(output);
+
+        let context_wrapper = PtrWrapper(self.context as *mut NetIfContext);
+        let output_wrapper = PtrWrapper(output);
+        EventLoop::get().run(|| unsafe {
+            let context_wrapper = context_wrapper;
+            let output_wrapper = output_wrapper;
+
+            (*context_wrapper.0).output = Some(output_wrapper.0);
+        });
+    }
+
+    /// Turns the netif into a stream of the packets it sends and a sink for
+    /// the packets it receives, to drive it from any async transport.
+    ///
+    /// Up to `capacity` packets wait in either direction. The sink is ready
+    /// again once the stack processed the packets queued on it. The stack
+    /// can't wait for the stream though, it drops the packets it sends while
+    /// the stream is full, like a NIC with a full ring. The netif is freed
+    /// once both halves are dropped.
+    pub fn split(mut self, capacity: usize) -> (Outbound, Inbound) {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.set_packet_output_fn(Box::new(move |packet: Packet| {
+            if sender.try_send(packet.into_bytes()).is_err() {
+                debug!("Outbound queue full, dropping a packet");
+            }
+        }));
+
+        let netif = Arc::new(self);
+        let outbound = Outbound { receiver, _netif: netif.clone() };
+        let inbound = Inbound {
+            netif,
+            capacity,
+            in_flight: Arc::new(InFlight { count: AtomicUsize::new(0), waker: AtomicWaker::new() }),
+        };
+        (outbound, inbound)
+    }
+
+    /// Carries the packets of the netif over `device` until it fails, then
+    /// frees the netif.
+    ///
+    /// The netif's MTU should match the device's.
+    pub async fn run<D: PacketDevice>(self, device: D) -> io::Result<()> {
+        let (outbound, inbound) = self.split(DEVICE_QUEUE_LEN);
+        drive(device, outbound, inbound).await
+    }
+
+    /// Builds a netif and carries its packets over `fd` on the current tokio
+    /// runtime, which needs the IO driver.
+    ///
+    /// `fd` is anything that carries one packet per read and write, like a
+    /// TUN device handed over by a VPN framework, or one end of a
+    /// `socketpair(SOCK_SEQPACKET)` in tests. Reads and writes are retried on
+    /// `EAGAIN` and `EINTR`; any other error, or the other end closing, ends
+    /// it and resolves the returned status.
+    #[cfg(target_os = "linux")]
+    pub fn from_fd(fd: OwnedFd, options: TunNetifBuilder, pipe: Box<dyn Pipe>) -> io::Result<DeviceStatus> {
+        let device = TunFd::new(fd, options.mtu)?;
+        let (outbound, inbound) = options.build(pipe).split(DEVICE_QUEUE_LEN);
+        let netif = inbound.netif.clone();
+        let task = tokio::spawn(drive(device, outbound, inbound));
+        Ok(DeviceStatus { task, netif })
+    }
+}
+
+async fn drive<D: PacketDevice>(device: D, outbound: Outbound, inbound: Inbound) -> io::Result<()> {
+    futures::try_join!(receive_from(&device, inbound), send_to(&device, outbound))?;
+    Ok(())
+}
+
+async fn receive_from<D: PacketDevice>(device: &D, mut inbound: Inbound) -> io::Result<()> {
+    let mut buf = vec![0; usize::from(device.mtu())];
+    loop {
+        let len = device.recv(&mut buf).await?;
+        if len == 0 {
+            continue;
+        }
+        inbound.send(Bytes::copy_from_slice(&buf[..len])).await?;
+    }
+}
+
+async fn send_to<D: PacketDevice>(device: &D, mut outbound: Outbound) -> io::Result<()> {
+    while let Some(packet) = outbound.next().await {
+        device.send(&packet).await?;
+    }
+    Ok(())
+}
+
+impl TunNetif {
+    /// Hands new connections to the returned stream instead of the `Pipe`.
+    ///
+    /// At most `backlog` connections, and at least one, wait in the stream.
+    /// While it is full, SYNs are answered with a RST instead of being queued
+    /// on the stack.
+    pub fn incoming(&mut self, backlog: usize) -> Incoming {
+        let (sender, receiver) = mpsc::channel(backlog.max(1));
+
+        // Every SYN from here on goes to the stream.
+        let context_wrapper = PtrWrapper(self.context as *mut NetIfContext);
+        EventLoop::get().run(move || unsafe {
+            let context_wrapper = context_wrapper;
+
+            (*context_wrapper.0).incoming = Some(sender);
+        });
+
+        Incoming { receiver }
+    }
+
+    /// Sets the receive buffer of new connections, which caps the window
+    /// announced to the client. See `TcpConnection::set_recv_buffer_size`
+    /// to change it for a single connection.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        let netif_wrapper = PtrWrapper(self.netif);
+        EventLoop::get().execute(move || unsafe {
+            let netif_wrapper = netif_wrapper;
+
+            let callback = (*netif_wrapper.0).state as *mut tun_device_callback;
+            (*callback).recv_window = u32::try_from(size).unwrap_or(u32::MAX);
+        });
+    }
+
+    /// Hands out new connections before the handshake is completed.
+    ///
+    /// The client only gets a SYN-ACK once `TcpConnection::accept` is called,
+    /// or a RST or ICMP error from `TcpConnection::reject`, so the outcome of
+    /// connecting upstream can be passed on. A connection that is neither
+    /// accepted nor rejected is aborted by the stack after 20 seconds.
+    pub fn set_deferred_handshake(&mut self, deferred: bool) {
+        let context_wrapper = PtrWrapper(self.context as *mut NetIfContext);
+        EventLoop::get().execute(move || unsafe {
+            let context_wrapper = context_wrapper;
+
+            (*context_wrapper.0).deferred_handshake = deferred;
+        });
+    }
+}
+
+impl TunNetif {
+    /// The connections handed out by this netif that weren't dropped yet.
+    ///
+    /// This blocks until the lwIP thread answers, async code should use
+    /// `connections_async`.
+    pub fn connections(&self) -> Vec<ConnectionSnapshot> {
+        EventLoop::get().run(self.snapshot_job())
+    }
+
+    /// Like `connections`, but waits without blocking the caller.
+    pub async fn connections_async(&self) -> Vec<ConnectionSnapshot> {
+        EventLoop::get().run_async(self.snapshot_job()).await
+    }
+
+    /// Aborts a connection, the client gets a RST. Returns whether it was
+    /// still open.
+    ///
+    /// This blocks until the lwIP thread answers, async code should use
+    /// `kill_async`.
+    pub fn kill(&self, id: ConnectionId) -> bool {
+        EventLoop::get().run(self.kill_job(id))
+    }
+
+    /// Like `kill`, but waits without blocking the caller.
+    pub async fn kill_async(&self, id: ConnectionId) -> bool {
+        EventLoop::get().run_async(self.kill_job(id)).await
+    }
+
+    // The netif outlives the jobs: its teardown is queued after them.
+    fn snapshot_job(&self) -> impl FnOnce() -> Vec<ConnectionSnapshot> + Send + 'static {
+        let context_wrapper = PtrWrapper(self.context);
+
+        move || unsafe {
+            let context_wrapper = context_wrapper;
+
+            (*context_wrapper.0).connections.snapshot()
+        }
+    }
+
+    fn kill_job(&self, id: ConnectionId) -> impl FnOnce() -> bool + Send + 'static {
+        let context_wrapper = PtrWrapper(self.context);
+
+        move || unsafe {
+            let context_wrapper = context_wrapper;
+
+            (*context_wrapper.0).connections.kill(id)
+        }
+    }
+
+    /// Closes the netif gracefully.
+    ///
+    /// SYNs are reset from now on and `incoming` ends. Every open connection
+    /// sends its FIN once what was written to it is sent, pending ones are
+    /// reset. Once the clients closed their side as well, or at `deadline`,
+    /// whatever is left is aborted and the netif is freed like on drop.
+    ///
+    /// Needs a tokio runtime with the time driver.
+    pub async fn shutdown(self, deadline: Instant) {
+        let event_loop = EventLoop::get();
+
+        let context_wrapper = PtrWrapper(self.context as *mut NetIfContext);
+        event_loop
+            .run_async(move || unsafe {
+                let context_wrapper = context_wrapper;
+                let context = &mut *context_wrapper.0;
+
+                context.shutting_down = true;
+                context.incoming = None;
+                context.connections.shutdown_all();
+            })
+            .await;
+
+        loop {
+            let netif_wrapper = PtrWrapper(self.netif);
+            let open = event_loop
+                .run_async(move || unsafe {
+                    let netif_wrapper = netif_wrapper;
+                    tun_netif_tcp_count(netif_wrapper.0)
+                })
+                .await;
+
+            let now = Instant::now();
+            if open == 0 || now >= deadline {
+                break;
+            }
+            let wake_up = deadline.min(now + DRAIN_CHECK_INTERVAL);
+            tokio::time::sleep_until(wake_up.into()).await;
+        }
+
+        // The job owns the netif from here on, even if this future is
+        // dropped while it waits for it.
+        let this = ManuallyDrop::new(self);
+        event_loop.run_async(this.teardown()).await;
+    }
+
+    // Aborts the connections left and frees the netif with everything of
+    // it, on the lwIP thread. `TcpConnection`s still around only see the
+    // error from then on, and `UdpFlow`s are closed.
+    fn teardown(&self) -> impl FnOnce() + Send + 'static {
+        let netif_wrapper = PtrWrapper(self.netif);
+        let context_wrapper = PtrWrapper(self.context as *mut NetIfContext);
+
+        move || unsafe {
+            let netif_wrapper = netif_wrapper;
+            let context_wrapper = context_wrapper;
+
+            // Their pcbs would outlive the netif otherwise.
+            #[cfg(feature = "udp")]
+            (*context_wrapper.0).flows.close_all();
+
+            let callback = (*netif_wrapper.0).state as *mut tun_device_callback;
+            tun_netif_remove(netif_wrapper.0);
+            drop(Box::from_raw(callback));
+
+            let context = Box::from_raw(context_wrapper.0);
+            EventLoop::get().release_timers(context.timer_policy);
         }
     }
 }
 
 impl Drop for TunNetif {
     fn drop(&mut self) {
-        unsafe {
-            crate::lwip_binding::netif_remove(self.netif);
-            _ = Box::from_raw(self.context as *mut NetIfContext);
-        }
+        // After whatever was queued for the netif so far, without waiting
+        // for it: the last reference may go away on an async task.
+        EventLoop::get().execute(self.teardown());
     }
 }
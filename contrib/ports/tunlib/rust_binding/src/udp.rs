@@ -0,0 +1,228 @@
+use crate::lwip_binding::{
+    err_enum_t_ERR_OK, err_t, ip_addr_t, pbuf, pbuf_alloc, pbuf_copy_partial, pbuf_free,
+    pbuf_layer_PBUF_TRANSPORT, pbuf_take, pbuf_type_PBUF_RAM, tun_udp_flow_send, udp_pcb,
+    udp_recv, udp_remove,
+};
+use crate::event_loop::EventLoop;
+use crate::tun::socket_addr_from_lwip;
+use core::task::{Context, Poll};
+use log::debug;
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::Waker;
+
+/// A single UDP 5-tuple seen on the tun device.
+///
+/// Datagrams the client sends to `local_addr` are queued for `recv`, and
+/// `send` answers them with `local_addr` as the source address, so the
+/// client sees the reply coming from the host it originally talked to.
+///
+/// The flow is closed once its netif is gone.
+pub struct UdpFlow {
+    local_addr: SocketAddr,
+
+    peer_addr: SocketAddr,
+
+    event_loop: &'static EventLoop,
+
+    callback: Arc<Mutex<Callback>>,
+}
+
+unsafe impl Send for UdpFlow {}
+unsafe impl Sync for UdpFlow {}
+
+struct Callback {
+    pcb: *mut udp_pcb,
+    // The pcb is removed, by the netif's teardown or on drop.
+    closed: bool,
+    recv_waker: Option<Waker>,
+    datagrams: VecDeque<Vec<u8>>,
+}
+
+unsafe impl Send for Callback {}
+
+// On the lwIP thread.
+unsafe fn close(locked: &mut Callback) {
+    if locked.closed {
+        return;
+    }
+    locked.closed = true;
+    udp_remove(locked.pcb);
+
+    if let Some(waker) = locked.recv_waker.take() {
+        waker.wake();
+    }
+}
+
+/// The flows a netif handed out that are still around.
+#[derive(Default)]
+pub(crate) struct Flows {
+    live: Mutex<Vec<Weak<Mutex<Callback>>>>,
+}
+
+impl Flows {
+    fn insert(&self, callback: &Arc<Mutex<Callback>>) {
+        let mut live = self.live.lock().unwrap();
+        live.retain(|callback| callback.strong_count() > 0);
+        live.push(Arc::downgrade(callback));
+    }
+
+    /// On the lwIP thread: removes the pcbs of the flows left, their `recv`
+    /// ends once they read what was queued.
+    pub(crate) unsafe fn close_all(&self) {
+        for callback in std::mem::take(&mut *self.live.lock().unwrap()) {
+            if let Some(callback) = callback.upgrade() {
+                close(&mut callback.lock().unwrap());
+            }
+        }
+    }
+}
+
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+
+extern "C" fn recv_function(
+    arg: *mut std::os::raw::c_void,
+    _: *mut udp_pcb,
+    p: *mut pbuf,
+    _: *const ip_addr_t,
+    _: u16,
+) {
+    let callback = arg as *const Mutex<Callback>;
+    let callback = unsafe { &*callback };
+
+    let mut datagram = vec![0u8; usize::from(unsafe { (*p).tot_len })];
+    unsafe {
+        pbuf_copy_partial(p, datagram.as_mut_ptr() as *mut c_void, (*p).tot_len, 0);
+        pbuf_free(p);
+    }
+
+    let mut locked = callback.lock().unwrap();
+
+    // Like a socket buffer, drop what the application is too slow to read.
+    if locked.datagrams.len() >= MAX_QUEUED_DATAGRAMS {
+        return;
+    }
+    locked.datagrams.push_back(datagram);
+
+    if let Some(waker) = locked.recv_waker.take() {
+        waker.wake();
+    }
+}
+
+impl UdpFlow {
+    /// Takes over a pcb lwIP just created for a flow, on the lwIP thread.
+    pub(crate) fn new(pcb: *mut udp_pcb, flows: &Flows) -> UdpFlow {
+        let event_loop = EventLoop::get();
+        debug_assert!(event_loop.is_current());
+
+        let callback = Arc::new(Mutex::new(Callback {
+            pcb,
+            closed: false,
+            recv_waker: None,
+            datagrams: VecDeque::new(),
+        }));
+        let ptr = Arc::as_ptr(&callback) as *mut Mutex<Callback>;
+
+        let (local_addr, peer_addr) = unsafe {
+            udp_recv(pcb, Some(recv_function), ptr as *mut c_void);
+
+            (
+                socket_addr_from_lwip(&(*pcb).local_ip, (*pcb).local_port),
+                socket_addr_from_lwip(&(*pcb).remote_ip, (*pcb).remote_port),
+            )
+        };
+
+        flows.insert(&callback);
+
+        UdpFlow {
+            local_addr,
+            peer_addr,
+            event_loop,
+            callback,
+        }
+    }
+
+    /// The address the client sent to, which replies are sent from.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The client's address.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
+        let mut locked = self.callback.lock().unwrap();
+
+        if let Some(datagram) = locked.datagrams.pop_front() {
+            return Poll::Ready(Some(datagram));
+        }
+        if locked.closed {
+            return Poll::Ready(None);
+        }
+
+        locked.recv_waker.replace(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Waits for the next datagram from the client, `None` once the flow is
+    /// closed.
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Sends `data` to the client as a single datagram.
+    ///
+    /// The datagram is queued for the lwIP thread. Like any UDP send, it may
+    /// still be dropped there, for lack of memory.
+    pub fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+        let len = u16::try_from(data.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "datagram too large")
+        })?;
+
+        if self.callback.lock().unwrap().closed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "the flow is closed",
+            ));
+        }
+
+        let callback = self.callback.clone();
+        let datagram = data.to_vec();
+
+        self.event_loop.execute(move || unsafe {
+            let locked = callback.lock().unwrap();
+            if locked.closed {
+                return;
+            }
+
+            let pbuf = pbuf_alloc(pbuf_layer_PBUF_TRANSPORT, len, pbuf_type_PBUF_RAM);
+            if pbuf.is_null() {
+                debug!("udp send failed, out of pbufs");
+                return;
+            }
+            pbuf_take(pbuf, datagram.as_ptr() as *const c_void, len);
+
+            let err_t = tun_udp_flow_send(locked.pcb, pbuf);
+            pbuf_free(pbuf);
+
+            if err_t != err_enum_t_ERR_OK as err_t {
+                debug!("udp send failed {}", err_t);
+            }
+        });
+
+        Ok(data.len())
+    }
+}
+
+impl Drop for UdpFlow {
+    fn drop(&mut self) {
+        // lwIP may deliver to the pcb until it is removed.
+        let callback = self.callback.clone();
+
+        self.event_loop.execute(move || unsafe { close(&mut callback.lock().unwrap()) });
+    }
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::{Segment, ACK, SYN, TIMEOUT};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tun::device::{ChannelDevice, PacketDevice};
+use tun::tcp::TcpConnection;
+use tun::tun::{ConnectionInfo, Pipe, TunNetif};
+#[cfg(feature = "udp")]
+use tun::udp::UdpFlow;
+
+// Hands everything the netif intercepts to the test.
+struct Forward {
+    connections: mpsc::UnboundedSender<(TcpConnection, ConnectionInfo)>,
+    #[cfg(feature = "udp")]
+    flows: mpsc::UnboundedSender<(UdpFlow, SocketAddr)>,
+}
+
+impl Pipe for Forward {
+    fn handle_new_connection(&self, conn: TcpConnection, info: ConnectionInfo) {
+        let _ = self.connections.send((conn, info));
+    }
+
+    #[cfg(feature = "udp")]
+    fn handle_new_udp_flow(&self, flow: UdpFlow, dst: SocketAddr) {
+        let _ = self.flows.send((flow, dst));
+    }
+}
+
+async fn next_packet(peer: &ChannelDevice) -> Vec<u8> {
+    let mut buf = [0; 1500];
+    let len = timeout(TIMEOUT, peer.recv(&mut buf)).await.unwrap().unwrap();
+    buf[..len].to_vec()
+}
+
+#[tokio::test]
+async fn runs_until_the_peer_is_dropped() {
+    let (device, peer) = ChannelDevice::pair(16, 1500);
+    let (connections, mut new_connections) = mpsc::unbounded_channel();
+    #[cfg(feature = "udp")]
+    let (flows, mut new_flows) = mpsc::unbounded_channel();
+    let pipe = Forward {
+        connections,
+        #[cfg(feature = "udp")]
+        flows,
+    };
+    let netif = TunNetif::builder()
+        .ipv4(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(255, 255, 255, 0),
+            Ipv4Addr::new(10, 0, 0, 1),
+        )
+        .build(Box::new(pipe));
+    let run = tokio::spawn(netif.run(device));
+
+    let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+    let server = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+    let syn = Segment { src: client, dst: server, seq: 100, ack: 0, flags: SYN };
+    peer.send(&common::tcp(syn)).await.unwrap();
+
+    let syn_ack = common::parse_tcp(&next_packet(&peer).await).expect("not a TCP segment");
+    assert_eq!((syn_ack.src, syn_ack.dst), (server, client));
+    assert_eq!(syn_ack.flags, SYN | ACK);
+    assert_eq!(syn_ack.ack, 101);
+
+    let ack = Segment { src: client, dst: server, seq: 101, ack: syn_ack.seq.wrapping_add(1), flags: ACK };
+    peer.send(&common::tcp(ack)).await.unwrap();
+
+    let (conn, info) = timeout(TIMEOUT, new_connections.recv()).await.unwrap().unwrap();
+    assert_eq!((info.src, info.dst), (SocketAddr::V4(client), SocketAddr::V4(server)));
+    drop(conn);
+
+    #[cfg(feature = "udp")]
+    {
+        let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 50000);
+        let resolver = SocketAddrV4::new(Ipv4Addr::new(9, 9, 9, 9), 53);
+        peer.send(&common::udp(client, resolver, b"ping")).await.unwrap();
+
+        let (flow, dst) = timeout(TIMEOUT, new_flows.recv()).await.unwrap().unwrap();
+        assert_eq!(dst, SocketAddr::V4(resolver));
+        assert_eq!(timeout(TIMEOUT, flow.recv()).await.unwrap().unwrap(), b"ping");
+
+        flow.send(b"pong").unwrap();
+        // Dropping the connection may have sent its RST first.
+        let reply = loop {
+            let packet = next_packet(&peer).await;
+            if let Some((src, dst, payload)) = common::parse_udp(&packet) {
+                break (src, dst, payload.to_vec());
+            }
+        };
+        assert_eq!(reply, (resolver, client, b"pong".to_vec()));
+        drop(flow);
+    }
+
+    drop(peer);
+    let err = timeout(TIMEOUT, run).await.unwrap().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,140 @@
+//! Hand-made IPv4 packets, to play the client on the other end of a device.
+
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+pub const FIN: u8 = 0x01;
+pub const SYN: u8 = 0x02;
+pub const RST: u8 = 0x04;
+pub const ACK: u8 = 0x10;
+
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+/// How long to wait for the stack to answer before giving up.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The fields of a TCP segment the tests look at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+}
+
+/// A TCP segment without options or payload.
+pub fn tcp(segment: Segment) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20);
+    tcp.extend_from_slice(&segment.src.port().to_be_bytes());
+    tcp.extend_from_slice(&segment.dst.port().to_be_bytes());
+    tcp.extend_from_slice(&segment.seq.to_be_bytes());
+    tcp.extend_from_slice(&segment.ack.to_be_bytes());
+    tcp.extend_from_slice(&[5 << 4, segment.flags]);
+    // The window, the checksum and the urgent pointer.
+    tcp.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+
+    let checksum = transport_checksum(*segment.src.ip(), *segment.dst.ip(), TCP, &tcp);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    ipv4(*segment.src.ip(), *segment.dst.ip(), TCP, &tcp)
+}
+
+pub fn parse_tcp(packet: &[u8]) -> Option<Segment> {
+    let (src, dst, tcp) = parse_ipv4(packet, TCP)?;
+    if tcp.len() < 20 {
+        return None;
+    }
+    Some(Segment {
+        src: SocketAddrV4::new(src, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst: SocketAddrV4::new(dst, u16::from_be_bytes([tcp[2], tcp[3]])),
+        seq: u32::from_be_bytes(tcp[4..8].try_into().unwrap()),
+        ack: u32::from_be_bytes(tcp[8..12].try_into().unwrap()),
+        flags: tcp[13],
+    })
+}
+
+/// What a TCP segment carries after its header.
+pub fn tcp_payload(packet: &[u8]) -> Option<&[u8]> {
+    let (_, _, tcp) = parse_ipv4(packet, TCP)?;
+    tcp.get(usize::from(*tcp.get(12)? >> 4) * 4..)
+}
+
+pub fn udp(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let len = 8 + payload.len() as u16;
+    let mut udp = Vec::with_capacity(usize::from(len));
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let checksum = transport_checksum(*src.ip(), *dst.ip(), UDP, &udp);
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    ipv4(*src.ip(), *dst.ip(), UDP, &udp)
+}
+
+/// The addresses and the payload of a UDP datagram.
+pub fn parse_udp(packet: &[u8]) -> Option<(SocketAddrV4, SocketAddrV4, &[u8])> {
+    let (src, dst, udp) = parse_ipv4(packet, UDP)?;
+    if udp.len() < 8 {
+        return None;
+    }
+    Some((
+        SocketAddrV4::new(src, u16::from_be_bytes([udp[0], udp[1]])),
+        SocketAddrV4::new(dst, u16::from_be_bytes([udp[2], udp[3]])),
+        &udp[8..],
+    ))
+}
+
+fn ipv4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    let len = 20 + payload.len() as u16;
+    let mut packet = Vec::with_capacity(usize::from(len));
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&len.to_be_bytes());
+    // No fragmentation, a TTL of 64 and the checksum, filled in below.
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+
+    let checksum = checksum(0, &packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn parse_ipv4(packet: &[u8], protocol: u8) -> Option<(Ipv4Addr, Ipv4Addr, &[u8])> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 || packet[9] != protocol {
+        return None;
+    }
+    let header_len = usize::from(packet[0] & 0x0f) * 4;
+    let len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    Some((src, dst, packet.get(header_len..len)?))
+}
+
+fn transport_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&src.octets());
+    pseudo.extend_from_slice(&dst.octets());
+    pseudo.extend_from_slice(&[0, protocol]);
+    pseudo.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+    checksum(sum(0, &pseudo), segment)
+}
+
+fn checksum(initial: u32, data: &[u8]) -> u16 {
+    let mut sum = sum(initial, data);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn sum(initial: u32, data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .fold(initial, |sum, word| sum + word)
+}
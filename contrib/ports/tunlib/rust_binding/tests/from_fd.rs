@@ -0,0 +1,55 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{Segment, ACK, SYN, TIMEOUT};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{FromRawFd, OwnedFd};
+use tokio::time::timeout;
+use tun::device::TunFd;
+use tun::tcp::TcpConnection;
+use tun::tun::{ConnectionInfo, Pipe, TunNetif};
+
+struct Ignore;
+
+impl Pipe for Ignore {
+    fn handle_new_connection(&self, _conn: TcpConnection, _info: ConnectionInfo) {}
+}
+
+fn seqpacket_pair() -> (OwnedFd, OwnedFd) {
+    let mut fds = [0; 2];
+    let result = unsafe {
+        libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr())
+    };
+    assert_eq!(result, 0, "socketpair: {}", io::Error::last_os_error());
+    unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+}
+
+#[tokio::test]
+async fn answers_a_syn_until_the_peer_closes() {
+    let (fd, peer) = seqpacket_pair();
+    let options = TunNetif::builder().ipv4(
+        Ipv4Addr::new(10, 0, 0, 1),
+        Ipv4Addr::new(255, 255, 255, 0),
+        Ipv4Addr::new(10, 0, 0, 1),
+    );
+    let status = TunNetif::from_fd(fd, options, Box::new(Ignore)).unwrap();
+    let peer = TunFd::new(peer, 1500).unwrap();
+
+    let client = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+    let server = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80);
+    let syn = Segment { src: client, dst: server, seq: 100, ack: 0, flags: SYN };
+    peer.write(&common::tcp(syn)).await.unwrap();
+
+    let mut buf = [0; 1500];
+    let len = timeout(TIMEOUT, peer.read(&mut buf)).await.unwrap().unwrap();
+    let syn_ack = common::parse_tcp(&buf[..len]).expect("not a TCP segment");
+    assert_eq!((syn_ack.src, syn_ack.dst), (server, client));
+    assert_eq!(syn_ack.flags, SYN | ACK);
+    assert_eq!(syn_ack.ack, 101);
+
+    drop(peer);
+    let err = timeout(TIMEOUT, status).await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
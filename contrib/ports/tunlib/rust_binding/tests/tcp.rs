@@ -0,0 +1,162 @@
+mod common;
+
+use common::{Segment, ACK, FIN, RST, SYN, TIMEOUT};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tun::device::{ChannelDevice, PacketDevice};
+use tun::tcp::TcpConnection;
+use tun::tun::{ConnectionInfo, Pipe, TunNetif, TunNetifBuilder};
+
+struct Forward(mpsc::UnboundedSender<TcpConnection>);
+
+impl Pipe for Forward {
+    fn handle_new_connection(&self, conn: TcpConnection, _info: ConnectionInfo) {
+        let _ = self.0.send(conn);
+    }
+}
+
+// The test's end of a connection, on the other end of the netif's device.
+struct Client {
+    peer: ChannelDevice,
+    addr: SocketAddrV4,
+    server: SocketAddrV4,
+    // The next sequence number to send, and the next one expected.
+    seq: u32,
+    ack: u32,
+}
+
+impl Client {
+    // Runs a netif built from `options` and connects to it from `port`.
+    async fn connect(options: TunNetifBuilder, port: u16) -> (Client, TcpConnection) {
+        let (device, peer) = ChannelDevice::pair(64, 1500);
+        let (connections, mut new_connections) = mpsc::unbounded_channel();
+        let netif = options
+            .ipv4(
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(255, 255, 255, 0),
+                Ipv4Addr::new(10, 0, 0, 1),
+            )
+            .build(Box::new(Forward(connections)));
+        tokio::spawn(netif.run(device));
+
+        let mut client = Client {
+            peer,
+            addr: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), port),
+            server: SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 80),
+            seq: 100,
+            ack: 0,
+        };
+        client.send(SYN).await;
+        let (syn_ack, _) = client.recv().await;
+        assert_eq!(syn_ack.flags, SYN | ACK);
+        client.ack = syn_ack.seq.wrapping_add(1);
+        client.send(ACK).await;
+
+        let conn = timeout(TIMEOUT, new_connections.recv()).await.unwrap().unwrap();
+        (client, conn)
+    }
+
+    async fn send(&mut self, flags: u8) {
+        let segment = Segment { src: self.addr, dst: self.server, seq: self.seq, ack: self.ack, flags };
+        self.peer.send(&common::tcp(segment)).await.unwrap();
+        if flags & (SYN | FIN) != 0 {
+            self.seq = self.seq.wrapping_add(1);
+        }
+    }
+
+    // The next segment from the server, with its payload.
+    async fn recv(&mut self) -> (Segment, Vec<u8>) {
+        let mut buf = [0; 1500];
+        loop {
+            let len = timeout(TIMEOUT, self.peer.recv(&mut buf)).await.unwrap().unwrap();
+            let packet = &buf[..len];
+            if let Some(segment) = common::parse_tcp(packet) {
+                return (segment, common::tcp_payload(packet).unwrap().to_vec());
+            }
+        }
+    }
+
+    // Takes in the next data the server sends in order, and acknowledges it.
+    async fn recv_data(&mut self) -> Vec<u8> {
+        loop {
+            let (segment, payload) = self.recv().await;
+            if payload.is_empty() {
+                continue;
+            }
+            let in_order = segment.seq == self.ack;
+            if in_order {
+                self.ack = self.ack.wrapping_add(payload.len() as u32);
+            }
+            self.send(ACK).await;
+            if in_order {
+                return payload;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn reset_wakes_the_reader_and_the_writer() {
+    let (mut client, conn) = Client::connect(TunNetif::builder(), 40001).await;
+    let (mut reader, mut writer) = tokio::io::split(conn);
+
+    let reading = tokio::spawn(async move {
+        let mut buf = [0; 1024];
+        reader.read(&mut buf).await
+    });
+    // The client acknowledges nothing, so this fills the send buffer.
+    let writing = tokio::spawn(async move { writer.write_all(&vec![0; 1 << 20]).await });
+
+    // Once data shows up, the writer is on its way to be parked.
+    while client.recv().await.1.is_empty() {}
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!reading.is_finished() && !writing.is_finished());
+
+    client.send(RST).await;
+
+    let err = timeout(TIMEOUT, reading).await.unwrap().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    let err = timeout(TIMEOUT, writing).await.unwrap().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+}
+
+#[tokio::test]
+async fn writes_beyond_u16_arrive_in_order() {
+    // Room for more than a `tcp_write` takes at once, but not for all of it.
+    let options = TunNetif::builder().send_buffer_size(80_000);
+    let (mut client, mut conn) = Client::connect(options, 40002).await;
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+    let written = conn.write(&data).await.unwrap();
+    assert!(written > usize::from(u16::MAX) && written < data.len());
+
+    let rest = data[written..].to_vec();
+    let writing = tokio::spawn(async move {
+        conn.write_all(&rest).await.unwrap();
+        conn
+    });
+
+    let mut received = Vec::new();
+    while received.len() < data.len() {
+        received.extend(client.recv_data().await);
+    }
+    assert!(received == data, "the data arrived garbled");
+    drop(timeout(TIMEOUT, writing).await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn writes_reach_the_client_after_its_fin() {
+    let (mut client, mut conn) = Client::connect(TunNetif::builder(), 40003).await;
+
+    client.send(FIN | ACK).await;
+    let mut buf = [0; 16];
+    assert_eq!(timeout(TIMEOUT, conn.read(&mut buf)).await.unwrap().unwrap(), 0);
+
+    conn.write_all(b"still here").await.unwrap();
+    conn.flush().await.unwrap();
+    assert_eq!(client.recv_data().await, b"still here");
+}
@@ -2,4 +2,5 @@
 #include "lwip/init.h"
 #include "lwip/netif.h"
 #include "lwip/pbuf.h"
-#include "lwip/timeouts.h"
\ No newline at end of file
+#include "lwip/timeouts.h"
+#include "lwip/stats.h"
\ No newline at end of file
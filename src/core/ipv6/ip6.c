@@ -626,44 +626,45 @@ ip6_input(struct pbuf *p, struct netif *inp)
   } else {
     /* start trying with inp. if that's not acceptable, start walking the
        list of configured netifs. */
-    if (ip6_input_accept(inp)) {
-      netif = inp;
-    } else {
-      netif = NULL;
-#if !IPV6_CUSTOM_SCOPES
-      /* Shortcut: stop looking for other interfaces if either the source or
-        * the destination has a scope constrained to this interface. Custom
-        * scopes may break the 1:1 link/interface mapping, however. */
-      if (ip6_addr_islinklocal(ip6_current_dest_addr()) ||
-          ip6_addr_islinklocal(ip6_current_src_addr())) {
-        goto netif_found;
-      }
-#endif /* !IPV6_CUSTOM_SCOPES */
-#if !LWIP_NETIF_LOOPBACK || LWIP_HAVE_LOOPIF
-      /* The loopback address is to be considered link-local. Packets to it
-        * should be dropped on other interfaces, as per RFC 4291 Sec. 2.5.3.
-        * Its implied scope means packets *from* the loopback address should
-        * not be accepted on other interfaces, either. These requirements
-        * cannot be implemented in the case that loopback traffic is sent
-        * across a non-loopback interface, however. */
-      if (ip6_addr_isloopback(ip6_current_dest_addr()) ||
-          ip6_addr_isloopback(ip6_current_src_addr())) {
-        goto netif_found;
-      }
-#endif /* !LWIP_NETIF_LOOPBACK || LWIP_HAVE_LOOPIF */
-#if !LWIP_SINGLE_NETIF
-      NETIF_FOREACH(netif) {
-        if (netif == inp) {
-          /* we checked that before already */
-          continue;
-        }
-        if (ip6_input_accept(netif)) {
-          break;
-        }
-      }
-#endif /* !LWIP_SINGLE_NETIF */
-    }
-netif_found:
+    netif = inp;
+//     if (ip6_input_accept(inp)) {
+//       netif = inp;
+//     } else {
+//       netif = NULL;
+// #if !IPV6_CUSTOM_SCOPES
+//       /* Shortcut: stop looking for other interfaces if either the source or
+//         * the destination has a scope constrained to this interface. Custom
+//         * scopes may break the 1:1 link/interface mapping, however. */
+//       if (ip6_addr_islinklocal(ip6_current_dest_addr()) ||
+//           ip6_addr_islinklocal(ip6_current_src_addr())) {
+//         goto netif_found;
+//       }
+// #endif /* !IPV6_CUSTOM_SCOPES */
+// #if !LWIP_NETIF_LOOPBACK || LWIP_HAVE_LOOPIF
+//       /* The loopback address is to be considered link-local. Packets to it
+//         * should be dropped on other interfaces, as per RFC 4291 Sec. 2.5.3.
+//         * Its implied scope means packets *from* the loopback address should
+//         * not be accepted on other interfaces, either. These requirements
+//         * cannot be implemented in the case that loopback traffic is sent
+//         * across a non-loopback interface, however. */
+//       if (ip6_addr_isloopback(ip6_current_dest_addr()) ||
+//           ip6_addr_isloopback(ip6_current_src_addr())) {
+//         goto netif_found;
+//       }
+// #endif /* !LWIP_NETIF_LOOPBACK || LWIP_HAVE_LOOPIF */
+// #if !LWIP_SINGLE_NETIF
+//       NETIF_FOREACH(netif) {
+//         if (netif == inp) {
+//           /* we checked that before already */
+//           continue;
+//         }
+//         if (ip6_input_accept(netif)) {
+//           break;
+//         }
+//       }
+// #endif /* !LWIP_SINGLE_NETIF */
+//     }
+// netif_found:
     LWIP_DEBUGF(IP6_DEBUG, ("ip6_input: packet accepted on interface %c%c\n",
         netif ? netif->name[0] : 'X', netif? netif->name[1] : 'X'));
   }
@@ -917,6 +917,8 @@ tcp_listen_with_backlog_and_err(struct tcp_pcb *pcb, u8_t backlog, err_t *err)
   lpcb->accepts_pending = 0;
   tcp_backlog_set(lpcb, backlog);
 #endif /* TCP_LISTEN_BACKLOG */
+  lpcb->defer_synack = 0;
+  lpcb->accept_rcv_wnd = 0;
   TCP_REG(&tcp_listen_pcbs.pcbs, (struct tcp_pcb *)lpcb);
   res = ERR_OK;
 done:
@@ -1412,7 +1414,8 @@ tcp_slowtmr_start:
       tcp_free(pcb2);
 
       tcp_active_pcbs_changed = 0;
-      TCP_EVENT_ERR(last_state, err_fn, err_arg, ERR_ABRT);
+      /* tell timeouts apart from aborts */
+      TCP_EVENT_ERR(last_state, err_fn, err_arg, ERR_TIMEOUT);
       if (tcp_active_pcbs_changed) {
         goto tcp_slowtmr_start;
       }
@@ -61,6 +61,8 @@
 #if LWIP_ND6_TCP_REACHABILITY_HINTS
 #include "lwip/nd6.h"
 #endif /* LWIP_ND6_TCP_REACHABILITY_HINTS */
+#include "lwip/icmp.h"
+#include "lwip/icmp6.h"
 
 #include <string.h>
 
@@ -99,6 +101,7 @@ static void tcp_listen_input(struct tcp_pcb_listen *pcb);
 static void tcp_timewait_input(struct tcp_pcb *pcb);
 
 static int tcp_input_delayed_close(struct tcp_pcb *pcb);
+static void tcp_input_unreach(struct pbuf *p, u8_t host);
 
 #if LWIP_TCP_SACK_OUT
 static void tcp_add_sack(struct tcp_pcb *pcb, u32_t left, u32_t right);
@@ -579,9 +582,11 @@ aborted:
         const ip_addr_t *dest_addr = ip_current_dest_addr();
         const ip_addr_t *src_addr = ip_current_src_addr();
 
+        u8_t refuse = NETIF_SYN_REFUSE_RESET;
+
         LWIP_ASSERT("TCP Header should only has SYN", flags == TCP_SYN);
 
-        struct tcp_pcb_listen* pcb_from_handler = (struct tcp_pcb_listen*)inp->has_new_tcp_connection_fn(inp, tcphdr, dest_addr, src_addr);
+        struct tcp_pcb_listen* pcb_from_handler = (struct tcp_pcb_listen*)inp->has_new_tcp_connection_fn(inp, tcphdr, dest_addr, src_addr, &refuse);
 
         if (pcb_from_handler) {
           tcp_listen_input(pcb_from_handler);
@@ -589,6 +594,18 @@ aborted:
           return;
           // Put this connection into listen state then feed it this SYN packet
         }
+
+        if ((refuse == NETIF_SYN_REFUSE_PORT_UNREACHABLE || refuse == NETIF_SYN_REFUSE_HOST_UNREACHABLE) &&
+            tcphdr_opt2 == NULL) {
+          tcp_input_unreach(p, refuse == NETIF_SYN_REFUSE_HOST_UNREACHABLE);
+          refuse = NETIF_SYN_REFUSE_DROP;
+        }
+        if (refuse != NETIF_SYN_REFUSE_RESET) {
+          LWIP_DEBUGF(TCP_RST_DEBUG, ("tcp_input: SYN refused by netif without reset.\n"));
+          TCP_STATS_INC(tcp.drop);
+          pbuf_free(p);
+          return;
+        }
       }
     }
 
@@ -613,6 +630,40 @@ dropped:
   pbuf_free(p);
 }
 
+/** Called from tcp_input to answer a SYN refused by the netif with an ICMP
+ * destination unreachable. The TCP header (including options) must be in the
+ * first pbuf.
+ *
+ * @param p the segment, payload pointing behind the TCP header
+ * @param host 1 for host unreachable, 0 for port unreachable
+ */
+static void
+tcp_input_unreach(struct pbuf *p, u8_t host)
+{
+  /* the ICMP message quotes the header, undo tcp_input's byte order conversion */
+  tcphdr->src = lwip_htons(tcphdr->src);
+  tcphdr->dest = lwip_htons(tcphdr->dest);
+  tcphdr->seqno = lwip_htonl(tcphdr->seqno);
+  tcphdr->ackno = lwip_htonl(tcphdr->ackno);
+  tcphdr->wnd = lwip_htons(tcphdr->wnd);
+
+  pbuf_header_force(p, (s16_t)(ip_current_header_tot_len() + TCPH_HDRLEN_BYTES(tcphdr)));
+
+#if LWIP_IPV6 && LWIP_ICMP6
+  if (ip_current_is_v6()) {
+    icmp6_dest_unreach(p, host ? ICMP6_DUR_ADDRESS : ICMP6_DUR_PORT);
+    return;
+  }
+#endif /* LWIP_IPV6 && LWIP_ICMP6 */
+#if LWIP_IPV4 && LWIP_ICMP
+  if (!ip_current_is_v6()) {
+    icmp_dest_unreach(p, host ? ICMP_DUR_HOST : ICMP_DUR_PORT);
+  }
+#endif /* LWIP_IPV4 && LWIP_ICMP */
+  LWIP_UNUSED_ARG(p);
+  LWIP_UNUSED_ARG(host);
+}
+
 /** Called from tcp_input to check for TF_CLOSED flag. This results in closing
  * and deallocating a pcb at the correct place to ensure no one references it
  * any more.
@@ -729,6 +780,9 @@ tcp_listen_input(struct tcp_pcb_listen *pcb)
 
     /* Parse any options in the SYN. */
     tcp_parseopt(npcb);
+    if ((pcb->accept_rcv_wnd != 0) && (pcb->accept_rcv_wnd < npcb->rcv_wnd)) {
+      npcb->rcv_wnd = npcb->rcv_ann_wnd = pcb->accept_rcv_wnd;
+    }
     npcb->snd_wnd = tcphdr->wnd;
     npcb->snd_wnd_max = npcb->snd_wnd;
 
@@ -745,6 +799,17 @@ tcp_listen_input(struct tcp_pcb_listen *pcb)
     }
 #endif
 
+    if (pcb->defer_synack) {
+      /* Let the application decide before the client sees a SYN|ACK. It
+         calls tun_tcp_accept_pending() to send it, or aborts the pcb. */
+      tcp_set_flags(npcb, TF_ACCEPTED);
+      TCP_EVENT_ACCEPT(pcb, npcb, pcb->callback_arg, ERR_INPROGRESS, rc);
+      if ((rc != ERR_OK) && (rc != ERR_ABRT)) {
+        tcp_abandon(npcb, 0);
+      }
+      return;
+    }
+
     /* Send a SYN|ACK together with the MSS option. */
     rc = tcp_enqueue_flags(npcb, TCP_SYN | TCP_ACK);
     if (rc != ERR_OK) {
@@ -968,6 +1033,11 @@ tcp_process(struct tcp_pcb *pcb)
         if (TCP_SEQ_BETWEEN(ackno, pcb->lastack + 1, pcb->snd_nxt)) {
           pcb->state = ESTABLISHED;
           LWIP_DEBUGF(TCP_DEBUG, ("TCP connection established %"U16_F" -> %"U16_F".\n", inseg.tcphdr->src, inseg.tcphdr->dest));
+          if (pcb->flags & TF_ACCEPTED) {
+            /* the application took the pcb when the SYN came in */
+            tcp_backlog_accepted(pcb);
+            err = ERR_OK;
+          } else
 #if LWIP_CALLBACK_API || TCP_LISTEN_BACKLOG
           if (pcb->listener == NULL) {
             /* listen pcb might be closed by now */
@@ -1,9 +1,60 @@
 #include "lwip/err.h"
+#include "lwip/ip.h"
 #include "lwip/ip4_addr.h"
 #include "lwip/netif.h"
 #include "lwip/priv/tcp_priv.h"
 #include "lwip/tcp.h"
 #include "lwip/tun.h"
+#include "lwip/udp.h"
+#include "lwip/prot/udp.h"
+
+#if LWIP_WND_SCALE
+/* scaled windows are announced in units of 1 << TCP_RCV_SCALE */
+#define TUN_MIN_RECV_WINDOW (4U << TCP_RCV_SCALE)
+#else /* LWIP_WND_SCALE */
+#define TUN_MIN_RECV_WINDOW TCP_MSS
+#endif /* LWIP_WND_SCALE */
+
+#ifndef LWIP_TCP_OPT_LEN_SACK_PERM
+/* only defined with LWIP_TCP_SACK_OUT, but clients offer it either way */
+#define LWIP_TCP_OPT_LEN_SACK_PERM 2
+#endif /* LWIP_TCP_OPT_LEN_SACK_PERM */
+
+/* every SYN gets a listener of its own, this is its arg */
+struct tun_listener {
+  tun_device_callback_t *callback;
+  struct tcp_pcb *pcb;
+  tun_syn_info_t syn;
+};
+
+/* the connection pcb a listener made holds it here until it is handed out,
+   allocated with the first netif */
+static u8_t tun_listener_ext_arg_id = LWIP_TCP_PCB_NUM_EXT_ARG_ID_INVALID;
+
+static void tun_listener_free(struct tun_listener *tun_listener) {
+  tcp_close(tun_listener->pcb);
+  mem_free(tun_listener);
+}
+
+/* The connection died in SYN_RCVD, reset, timed out or aborted, so its
+   listener never sees an accept. */
+static void tun_listener_destroyed(u8_t id, void *data) {
+  LWIP_UNUSED_ARG(id);
+  if (data != NULL) {
+    tun_listener_free((struct tun_listener *)data);
+  }
+}
+
+static err_t tun_listener_passive_open(u8_t id, struct tcp_pcb_listen *lpcb, struct tcp_pcb *cpcb) {
+  tcp_ext_arg_set_callbacks(cpcb, id, lpcb->ext_args[id].callbacks);
+  tcp_ext_arg_set(cpcb, id, lpcb->callback_arg);
+  return ERR_OK;
+}
+
+static const struct tcp_ext_arg_callbacks tun_listener_ext_args = {
+  tun_listener_destroyed,
+  tun_listener_passive_open
+};
 
 void tun_init() {
   netif_init();
@@ -11,39 +62,263 @@ void tun_init() {
 }
 
 err_t tun_device_tcp_accept(void *arg, struct tcp_pcb *newpcb, err_t err) {
+  struct tun_listener* tun_listener = (struct tun_listener *)arg;
+  tun_device_callback_t* callback = tun_listener->callback;
+
+  if (newpcb != NULL) {
+    /* released below, the callback may abort newpcb */
+    tcp_ext_arg_set(newpcb, tun_listener_ext_arg_id, NULL);
+    if (err == ERR_OK || err == ERR_INPROGRESS) {
+      err = callback->new_connection(callback->arg, newpcb, &tun_listener->syn, err);
+    }
+  }
+
+  /* every listener is made for a single SYN and has done its job now,
+     even if there was no memory for the connection */
+  tun_listener_free(tun_listener);
+
+  return err;
+}
+
+err_t tun_tcp_accept_pending(struct tcp_pcb *pcb) {
+  err_t err;
+
+  if (pcb->state != SYN_RCVD) {
+    return ERR_CONN;
+  }
+
+  err = tcp_enqueue_flags(pcb, TCP_SYN | TCP_ACK);
   if (err != ERR_OK) {
     return err;
   }
-  tun_device_callback_t* callback = (tun_device_callback_t *)arg;
-  return callback->new_connection(callback->arg, newpcb, err);
+
+  return tcp_output(pcb);
 }
 
-struct tcp_pcb* tun_device_has_new_tcp_connection(struct netif *netif, struct tcp_hdr *tcp_hdr, const ip_addr_t *dst_ip, const ip_addr_t *src_ip) {
+static void tun_parse_syn(struct netif *netif, struct tcp_hdr *tcp_hdr, tun_syn_info_t *syn) {
+  /* the pool pbufs always hold all headers of a SYN, so the options are
+     right behind tcp_hdr */
+  const u8_t *opts = (const u8_t *)tcp_hdr + TCP_HLEN;
+  u16_t optlen = TCPH_HDRLEN_BYTES(tcp_hdr) - TCP_HLEN;
+  u16_t i = 0;
+
+  syn->netif_idx = netif_get_index(netif);
+#if LWIP_IPV6
+  if (ip_current_is_v6()) {
+    syn->ttl = IP6H_HOPLIM(ip6_current_header());
+    syn->tos = IP6H_TC(ip6_current_header());
+  } else
+#endif /* LWIP_IPV6 */
+  {
+    syn->ttl = IPH_TTL(ip4_current_header());
+    syn->tos = IPH_TOS(ip4_current_header());
+  }
+  syn->mss = 0;
+  syn->wscale = TUN_SYN_NO_WSCALE;
+  syn->sack_permitted = 0;
+
+  while (i < optlen) {
+    u8_t kind = opts[i];
+    u8_t len;
+
+    if (kind == LWIP_TCP_OPT_EOL) {
+      break;
+    }
+    if (kind == LWIP_TCP_OPT_NOP) {
+      i++;
+      continue;
+    }
+    if (i + 1 >= optlen) {
+      break;
+    }
+    len = opts[i + 1];
+    if (len < 2 || i + len > optlen) {
+      /* malformed, keep what we have */
+      break;
+    }
+
+    switch (kind) {
+      case LWIP_TCP_OPT_MSS:
+        if (len == LWIP_TCP_OPT_LEN_MSS) {
+          syn->mss = (u16_t)((opts[i + 2] << 8) | opts[i + 3]);
+        }
+        break;
+      case LWIP_TCP_OPT_WS:
+        if (len == LWIP_TCP_OPT_LEN_WS) {
+          syn->wscale = opts[i + 2];
+        }
+        break;
+      case LWIP_TCP_OPT_SACK_PERM:
+        if (len == LWIP_TCP_OPT_LEN_SACK_PERM) {
+          syn->sack_permitted = 1;
+        }
+        break;
+      default:
+        break;
+    }
+    i += len;
+  }
+}
+
+struct tcp_pcb* tun_device_has_new_tcp_connection(struct netif *netif, struct tcp_hdr *tcp_hdr, const ip_addr_t *dst_ip, const ip_addr_t *src_ip, u8_t *refuse) {
+  tun_device_callback_t* callback = (tun_device_callback_t *)netif->state;
+  tun_syn_decision_t decision = TUN_SYN_ACCEPT;
+  struct tun_listener* tun_listener;
   struct tcp_pcb* conn;
+  struct tcp_pcb* listener;
   err_t err;
 
-  conn = tcp_new();
+  if (callback->accept_new_connection != NULL) {
+    decision = callback->accept_new_connection(callback->arg, src_ip, tcp_hdr->src, dst_ip, tcp_hdr->dest);
+  }
+
+  switch (decision) {
+    case TUN_SYN_ACCEPT:
+    case TUN_SYN_ACCEPT_DEFERRED:
+      break;
+    case TUN_SYN_PORT_UNREACHABLE:
+      *refuse = NETIF_SYN_REFUSE_PORT_UNREACHABLE;
+      return NULL;
+    case TUN_SYN_HOST_UNREACHABLE:
+      *refuse = NETIF_SYN_REFUSE_HOST_UNREACHABLE;
+      return NULL;
+    case TUN_SYN_DROP:
+      *refuse = NETIF_SYN_REFUSE_DROP;
+      return NULL;
+    default:
+      *refuse = NETIF_SYN_REFUSE_RESET;
+      return NULL;
+  }
 
-  tcp_arg(conn, netif->state);
+  tun_listener = (struct tun_listener *)mem_malloc(sizeof(struct tun_listener));
+  if (tun_listener == NULL) {
+    *refuse = NETIF_SYN_REFUSE_DROP;
+    return NULL;
+  }
+  tun_listener->callback = callback;
+  tun_parse_syn(netif, tcp_hdr, &tun_listener->syn);
+
+  conn = tcp_new_ip_type(IP_GET_TYPE(dst_ip));
+  if (conn == NULL) {
+    mem_free(tun_listener);
+    *refuse = NETIF_SYN_REFUSE_DROP;
+    return NULL;
+  }
+
+  tcp_arg(conn, tun_listener);
+
+  /* other clients may be connected to the same destination already */
+  ip_set_option(conn, SOF_REUSEADDR);
 
   err = tcp_bind(conn, dst_ip, tcp_hdr->dest);
+  if (err != ERR_OK) {
+    tcp_close(conn);
+    mem_free(tun_listener);
+    *refuse = NETIF_SYN_REFUSE_DROP;
+    return NULL;
+  }
+  /* the connection inherits this, see tun_netif_remove() */
+  tcp_bind_netif(conn, netif);
 
-  conn = tcp_listen(conn);
+  listener = tcp_listen(conn);
+  if (listener == NULL) {
+    /* out of listen pcbs, conn is left as it was */
+    tcp_close(conn);
+    mem_free(tun_listener);
+    *refuse = NETIF_SYN_REFUSE_DROP;
+    return NULL;
+  }
+  conn = listener;
+  tun_listener->pcb = conn;
 
   tcp_accept(conn, tun_device_tcp_accept);
+  tcp_ext_arg_set_callbacks(conn, tun_listener_ext_arg_id, &tun_listener_ext_args);
+
+  /* only this SYN may reach the listener, another client connecting to the
+     same destination has to go through the hook as well */
+  TCP_RMV(&tcp_listen_pcbs.pcbs, conn);
+
+  if (decision == TUN_SYN_ACCEPT_DEFERRED) {
+    ((struct tcp_pcb_listen *)conn)->defer_synack = 1;
+  }
+  if (callback->recv_window != 0) {
+    ((struct tcp_pcb_listen *)conn)->accept_rcv_wnd = LWIP_MAX(callback->recv_window, TUN_MIN_RECV_WINDOW);
+  }
 
   return conn;
 }
 
+#if LWIP_UDP
+struct udp_pcb* tun_device_has_new_udp_flow(struct netif *netif, struct udp_hdr *udp_hdr, const ip_addr_t *dst_ip, const ip_addr_t *src_ip) {
+  tun_device_callback_t* callback = (tun_device_callback_t *)netif->state;
+  struct udp_pcb* pcb;
+
+  if (callback->new_udp_flow == NULL) {
+    return NULL;
+  }
+
+  pcb = udp_new_ip_type(IP_GET_TYPE(dst_ip));
+  if (pcb == NULL) {
+    return NULL;
+  }
+
+  /* Several clients may talk to the same destination, so every flow binds
+     the same local address and is told apart by its remote end. */
+  ip_set_option(pcb, SOF_REUSEADDR);
+  udp_bind_netif(pcb, netif);
+
+  if (udp_bind(pcb, dst_ip, lwip_ntohs(udp_hdr->dest)) != ERR_OK ||
+      udp_connect(pcb, src_ip, lwip_ntohs(udp_hdr->src)) != ERR_OK) {
+    udp_remove(pcb);
+    return NULL;
+  }
+
+  if (callback->new_udp_flow(callback->arg, pcb) != ERR_OK) {
+    udp_remove(pcb);
+    return NULL;
+  }
+
+  return pcb;
+}
+
+err_t tun_udp_flow_send(struct udp_pcb *pcb, struct pbuf *p) {
+  struct netif *netif = netif_get_by_index(pcb->netif_idx);
+
+  if (netif == NULL) {
+    return ERR_RTE;
+  }
+
+  /* udp_send() refuses a local address the netif doesn't own, but replies
+     have to come from the original destination. */
+  return udp_sendto_if_src(pcb, p, &pcb->remote_ip, pcb->remote_port, netif, &pcb->local_ip);
+}
+#endif /* LWIP_UDP */
+
 err_t tun_device_output(struct netif *netif, struct pbuf *p, const ip4_addr_t *ipaddr) {
   tun_device_callback_t* callback = (tun_device_callback_t *)netif->state;
-  callback->output(callback->arg, netif, p, ipaddr);
+  callback->output(callback->arg, netif, p);
   return ERR_OK;
 }
 
+#if LWIP_IPV6
+err_t tun_device_output_ip6(struct netif *netif, struct pbuf *p, const ip6_addr_t *ipaddr) {
+  tun_device_callback_t* callback = (tun_device_callback_t *)netif->state;
+  callback->output(callback->arg, netif, p);
+  return ERR_OK;
+}
+#endif /* LWIP_IPV6 */
+
 err_t tun_netif_init(struct netif *netif) {
   netif->has_new_tcp_connection_fn = tun_device_has_new_tcp_connection;
+#if LWIP_UDP
+  netif->has_new_udp_flow_fn = tun_device_has_new_udp_flow;
+#else /* LWIP_UDP */
+  netif->has_new_udp_flow_fn = NULL;
+#endif /* LWIP_UDP */
   netif->output = tun_device_output;
+#if LWIP_IPV6
+  netif->output_ip6 = tun_device_output_ip6;
+#endif /* LWIP_IPV6 */
 
   netif_set_flags(netif, NETIF_FLAG_BROADCAST);
   netif_set_link_up(netif);
@@ -58,6 +333,10 @@ struct netif* tun_netif_new(u32_t ip_addr, u32_t netmask, u32_t gw_addr, tun_dev
 {
   struct netif *netif = mem_malloc(sizeof(struct netif));
 
+  if (tun_listener_ext_arg_id == LWIP_TCP_PCB_NUM_EXT_ARG_ID_INVALID) {
+    tun_listener_ext_arg_id = tcp_ext_arg_alloc_id();
+  }
+
   ip4_addr_t ip_addr_t = { .addr = ip_addr };
   ip4_addr_t netmask_t = { .addr = netmask };
   ip4_addr_t gw_addr_t = { .addr = gw_addr };
@@ -66,3 +345,75 @@ struct netif* tun_netif_new(u32_t ip_addr, u32_t netmask, u32_t gw_addr, tun_dev
 
   return netif;
 }
+
+void tun_netif_set_mtu(struct netif *netif, u16_t mtu)
+{
+  netif->mtu = mtu;
+#if LWIP_IPV6 && LWIP_ND6_ALLOW_RA_UPDATES
+  netif->mtu6 = mtu;
+#endif /* LWIP_IPV6 && LWIP_ND6_ALLOW_RA_UPDATES */
+}
+
+u32_t tun_netif_tcp_count(struct netif *netif)
+{
+  u8_t idx = netif_get_index(netif);
+  struct tcp_pcb *pcb;
+  u32_t count = 0;
+
+  for (pcb = tcp_active_pcbs; pcb != NULL; pcb = pcb->next) {
+    if (pcb->netif_idx == idx) {
+      count++;
+    }
+  }
+
+  return count;
+}
+
+static void tun_abort_pcblist(struct tcp_pcb *pcb_list, u8_t idx)
+{
+  struct tcp_pcb *pcb = pcb_list;
+
+  while (pcb != NULL) {
+    /* tcp_abort() unlinks the pcb */
+    struct tcp_pcb *next = pcb->next;
+    if (pcb->netif_idx == idx) {
+      tcp_abort(pcb);
+    }
+    pcb = next;
+  }
+}
+
+void tun_netif_remove(struct netif *netif)
+{
+  u8_t idx = netif_get_index(netif);
+
+  tun_abort_pcblist(tcp_active_pcbs, idx);
+  tun_abort_pcblist(tcp_tw_pcbs, idx);
+
+  netif_remove(netif);
+  mem_free(netif);
+}
+
+err_t tun_netif_add_ip6_address(struct netif *netif, const u32_t *addr)
+{
+#if LWIP_IPV6
+  ip6_addr_t ip6_addr_t;
+  s8_t idx;
+  err_t err;
+
+  IP6_ADDR(&ip6_addr_t, addr[0], addr[1], addr[2], addr[3]);
+
+  err = netif_add_ip6_address(netif, &ip6_addr_t, &idx);
+  if (err != ERR_OK) {
+    return err;
+  }
+  /* nobody is around to answer duplicate address detection */
+  netif_ip6_addr_set_state(netif, idx, IP6_ADDR_PREFERRED);
+
+  return ERR_OK;
+#else /* LWIP_IPV6 */
+  LWIP_UNUSED_ARG(netif);
+  LWIP_UNUSED_ARG(addr);
+  return ERR_VAL;
+#endif /* LWIP_IPV6 */
+}
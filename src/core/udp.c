@@ -310,6 +310,12 @@ udp_input(struct pbuf *p, struct netif *inp)
     pcb = uncon_pcb;
   }
 
+  /* still nothing? give the netif a chance to create a pcb for this flow */
+  if ((pcb == NULL) && !broadcast && !ip_addr_ismulticast(ip_current_dest_addr()) &&
+      (inp->has_new_udp_flow_fn != NULL)) {
+    pcb = inp->has_new_udp_flow_fn(inp, udphdr, ip_current_dest_addr(), ip_current_src_addr());
+  }
+
   /* Check checksum if this is a match or if it was directed at us. */
   if (pcb != NULL) {
     for_us = 1;
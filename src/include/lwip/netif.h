@@ -154,6 +154,8 @@ enum lwip_internal_netif_client_data_index
 
 struct netif;
 struct tcp_hdr;
+struct udp_hdr;
+struct udp_pcb;
 
 /** MAC Filter Actions, these are passed to a netif's igmp_mac_filter or
  * mld_mac_filter callback function. */
@@ -195,7 +197,13 @@ typedef err_t (*netif_output_fn)(struct netif *netif, struct pbuf *p,
 #endif /* LWIP_IPV4*/
 
 // #if TUN_DEVICE
-typedef struct tcp_pcb* (*netif_has_new_tcp_connection_fn)(struct netif *netif, struct tcp_hdr *tcp_hdr, const ip_addr_t *dst_ip, const ip_addr_t *src_ip);
+/** How tcp_input answers a SYN for which has_new_tcp_connection_fn returned no pcb */
+#define NETIF_SYN_REFUSE_RESET             0
+#define NETIF_SYN_REFUSE_PORT_UNREACHABLE  1
+#define NETIF_SYN_REFUSE_HOST_UNREACHABLE  2
+#define NETIF_SYN_REFUSE_DROP              3
+typedef struct tcp_pcb* (*netif_has_new_tcp_connection_fn)(struct netif *netif, struct tcp_hdr *tcp_hdr, const ip_addr_t *dst_ip, const ip_addr_t *src_ip, u8_t *refuse);
+typedef struct udp_pcb* (*netif_has_new_udp_flow_fn)(struct netif *netif, struct udp_hdr *udp_hdr, const ip_addr_t *dst_ip, const ip_addr_t *src_ip);
 // #endif /* TUN_DEVICE */
 
 #if LWIP_IPV6
@@ -367,6 +375,7 @@ struct netif {
   u8_t ip6_autoconfig_enabled;
 #endif /* LWIP_IPV6_AUTOCONFIG */
   netif_has_new_tcp_connection_fn has_new_tcp_connection_fn;
+  netif_has_new_udp_flow_fn has_new_udp_flow_fn;
 #if LWIP_IPV6_SEND_ROUTER_SOLICIT
   /** Number of Router Solicitation messages that remain to be sent. */
   u8_t rs_count;
@@ -114,7 +114,8 @@ typedef err_t (*tcp_poll_fn)(void *arg, struct tcp_pcb *tpcb);
  *
  * @param arg Additional argument to pass to the callback function (@see tcp_arg())
  * @param err Error code to indicate why the pcb has been closed
- *            ERR_ABRT: aborted through tcp_abort or by a TCP timer
+ *            ERR_ABRT: aborted through tcp_abort
+ *            ERR_TIMEOUT: aborted by a TCP timer
  *            ERR_RST: the connection was reset by the remote host
  */
 typedef void  (*tcp_err_fn)(void *arg, err_t err);
@@ -235,6 +236,13 @@ struct tcp_pcb_listen {
   u8_t backlog;
   u8_t accepts_pending;
 #endif /* TCP_LISTEN_BACKLOG */
+
+  /* Hand new connections to the accept callback (with ERR_INPROGRESS) before
+     the SYN|ACK is sent, see tun_tcp_accept_pending() */
+  u8_t defer_synack;
+
+  /* Receive window of new connections if smaller than TCP_WND, 0 for TCP_WND */
+  tcpwnd_size_t accept_rcv_wnd;
 };
 
 
@@ -270,6 +278,7 @@ struct tcp_pcb {
 #if LWIP_TCP_SACK_OUT
 #define TF_SACK        0x1000U /* Selective ACKs enabled */
 #endif
+#define TF_ACCEPTED    0x2000U /* Handed to the application before the SYN|ACK, no accept event when established */
 
   /* the rest of the fields are in host byte order
      as we have to do some math with them */
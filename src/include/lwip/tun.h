@@ -1,17 +1,78 @@
 #include "lwip/err.h"
 #include "lwip/netif.h"
 #include "lwip/tcp.h"
+#include "lwip/udp.h"
+
+struct udp_pcb;
+
+/* what to do with a SYN, see accept_new_connection */
+typedef enum {
+  TUN_SYN_ACCEPT = 0,
+  /* hand out the connection now, but only answer the SYN on tun_tcp_accept_pending() */
+  TUN_SYN_ACCEPT_DEFERRED,
+  TUN_SYN_RESET,
+  TUN_SYN_PORT_UNREACHABLE,
+  TUN_SYN_HOST_UNREACHABLE,
+  TUN_SYN_DROP
+} tun_syn_decision_t;
+
+#define TUN_SYN_NO_WSCALE 0xFF
+
+/* what the SYN of a connection carried */
+struct tun_syn_info {
+  u8_t netif_idx;
+  /* hop limit and traffic class for IPv6 */
+  u8_t ttl;
+  u8_t tos;
+  /* 0 without the option */
+  u16_t mss;
+  /* TUN_SYN_NO_WSCALE without the option */
+  u8_t wscale;
+  u8_t sack_permitted;
+};
+
+typedef struct tun_syn_info tun_syn_info_t;
 
 struct tun_device_callback {
-  err_t (*new_connection)(void *arg, struct tcp_pcb *newpcb, err_t err);
+  /* asked on every SYN before anything is allocated, ports in host byte order */
+  tun_syn_decision_t (*accept_new_connection)(void *arg, const ip_addr_t *src_ip, u16_t src_port, const ip_addr_t *dst_ip, u16_t dst_port);
+
+  /* err is ERR_INPROGRESS for a deferred connection that still waits for its SYN|ACK */
+  err_t (*new_connection)(void *arg, struct tcp_pcb *newpcb, const tun_syn_info_t *syn, err_t err);
+
+  /* only called with LWIP_UDP */
+  err_t (*new_udp_flow)(void *arg, struct udp_pcb *pcb);
 
-  err_t (*output)(void *arg, struct netif *netif, struct pbuf *p, const ip4_addr_t *ipaddr);
+  err_t (*output)(void *arg, struct netif *netif, struct pbuf *p);
 
   void *arg;
+
+  /* receive window of new connections, 0 for TCP_WND */
+  u32_t recv_window;
 };
 
 typedef struct tun_device_callback tun_device_callback_t;
 
 struct netif* tun_netif_new(u32_t ip_addr, u32_t netmask, u32_t gw_addr, tun_device_callback_t *callback);
 
+/* addr holds the four words of the address in network byte order */
+err_t tun_netif_add_ip6_address(struct netif *netif, const u32_t *addr);
+
+/* sets the MTU of both IPv4 and IPv6 */
+void tun_netif_set_mtu(struct netif *netif, u16_t mtu);
+
+/* connections of the netif that are still open, TIME_WAIT doesn't count */
+u32_t tun_netif_tcp_count(struct netif *netif);
+
+/* aborts every connection of the netif, then removes and frees it, but
+   not its state */
+void tun_netif_remove(struct netif *netif);
+
+#if LWIP_UDP
+err_t tun_udp_flow_send(struct udp_pcb *pcb, struct pbuf *p);
+#endif /* LWIP_UDP */
+
+/* sends the SYN|ACK of a connection accepted with TUN_SYN_ACCEPT_DEFERRED */
+err_t tun_tcp_accept_pending(struct tcp_pcb *pcb);
+
 void tun_init();